@@ -0,0 +1,118 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// Compares the memory used by the allocator's size classes with rounding
+// every object up to a power of two, for a few allocation patterns,
+// then measures how much shared memory is left stranded by alloc/free churn.
+
+use rand::Rng;
+use shared_data::ShmemAllocator;
+
+// The size an object would take if it was rounded up to a power of two.
+fn power_of_two_size(size: usize) -> usize {
+    usize::max(32, size + ShmemAllocator::OBJECT_HEADER_SIZE).next_power_of_two()
+}
+
+fn run(name: &str, sizes: &[usize]) {
+    let alloc = ShmemAllocator::create().unwrap();
+    let addresses: Vec<_> = sizes
+        .iter()
+        .map(|&size| alloc.alloc_bytes(size).unwrap())
+        .collect();
+    let requested: usize = sizes.iter().sum();
+    let power_of_two: usize = sizes.iter().map(|&size| power_of_two_size(size)).sum();
+    let size_classes = alloc.stats().bytes_allocated as usize;
+    println!(
+        "{}: {} objects, {} bytes requested",
+        name,
+        sizes.len(),
+        requested
+    );
+    println!(
+        "  powers of two: {} bytes ({:.1}% overhead)",
+        power_of_two,
+        overhead(power_of_two, requested)
+    );
+    println!(
+        "  size classes:  {} bytes ({:.1}% overhead)",
+        size_classes,
+        overhead(size_classes, requested)
+    );
+    println!(
+        "  saved {} bytes ({:.1}%)",
+        power_of_two as isize - size_classes as isize,
+        100.0 - 100.0 * size_classes as f64 / power_of_two as f64
+    );
+    for address in addresses {
+        alloc.free_bytes(address).unwrap();
+    }
+    // Nothing borrowed from the heap is still in use.
+    unsafe { ShmemAllocator::close(alloc) };
+}
+
+// Allocates objects whose sizes drift upwards, freeing most of the older objects
+// as it goes, then compares the shared memory in use with the bytes still live.
+fn churn(rng: &mut impl Rng) {
+    let alloc = ShmemAllocator::create().unwrap();
+    let mut live = Vec::new();
+    let mut peak = 0;
+    for round in 0..8 {
+        let min = 32 << round;
+        for _ in 0..2_000 {
+            let size = rng.gen_range(min, 2 * min);
+            live.push((size, alloc.alloc_bytes(size).unwrap()));
+        }
+        live.retain(|&(_, address)| {
+            if rng.gen_ratio(3, 4) {
+                alloc.free_bytes(address).unwrap();
+                false
+            } else {
+                true
+            }
+        });
+        peak = usize::max(peak, alloc.stats().total_size());
+    }
+    let requested: usize = live.iter().map(|&(size, _)| size).sum();
+    let in_use = alloc.stats().total_size();
+    println!(
+        "churn: {} live objects, {} bytes requested",
+        live.len(),
+        requested
+    );
+    println!(
+        "  shared memory: {} bytes ({:.1}% overhead), {} bytes at peak",
+        in_use,
+        overhead(in_use, requested),
+        peak
+    );
+    for (_, address) in live {
+        alloc.free_bytes(address).unwrap();
+    }
+    // Slabs are released once all their objects are freed, so nothing should be left.
+    println!(
+        "  after freeing everything: {} bytes",
+        alloc.stats().total_size()
+    );
+    unsafe { ShmemAllocator::close(alloc) };
+}
+
+fn overhead(used: usize, requested: usize) -> f64 {
+    100.0 * (used as f64 - requested as f64) / requested as f64
+}
+
+fn main() {
+    let mut rng = rand::thread_rng();
+    // The contents of a `SharedRc`, whose 65 bytes used to take 128.
+    run("rc contents", &vec![65; 10_000]);
+    // Vectors of u64 with just over a power of two elements.
+    let vectors: Vec<_> = (4..16).map(|log| ((1 << log) + 1) * 8).collect();
+    run("vectors", &vectors);
+    // Uniformly distributed small objects.
+    let small: Vec<_> = (0..10_000).map(|_| rng.gen_range(1, 4096)).collect();
+    run("small objects", &small);
+    // Large objects, which get shared memory of their own.
+    let large: Vec<_> = (0..8).map(|_| rng.gen_range(2 << 20, 16 << 20)).collect();
+    run("large objects", &large);
+    churn(&mut rng);
+}
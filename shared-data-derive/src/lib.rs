@@ -0,0 +1,174 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Derive macros for the `SharedMemCast` and `SharedMemRef` traits.
+//!
+//! The generated impls are checked at compile time: the type must be a
+//! `#[repr(C)]` struct which is not `#[repr(packed)]`, and every field must
+//! implement the derived trait.
+//! The only unsafe code is the impl itself, which is marked `#[allow(unsafe_code)]`,
+//! so crates using the derives can still be `#![deny(unsafe_code)]`.
+
+extern crate proc_macro;
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use quote::quote_spanned;
+use syn::parse_macro_input;
+use syn::parse_quote;
+use syn::spanned::Spanned;
+use syn::Data;
+use syn::DeriveInput;
+use syn::Error;
+use syn::Meta;
+use syn::NestedMeta;
+use syn::Path;
+
+/// Derives `SharedMemCast` for a `#[repr(C)]` struct whose fields are all `SharedMemCast`.
+///
+/// ```
+/// # use shared_data::SharedMemCast;
+/// #[derive(SharedMemCast)]
+/// #[repr(C)]
+/// struct Point {
+///     x: u32,
+///     y: u32,
+/// }
+/// ```
+///
+/// Structs without a stable layout are rejected.
+///
+/// ```compile_fail
+/// # use shared_data::SharedMemCast;
+/// #[derive(SharedMemCast)]
+/// struct Point {
+///     x: u32,
+///     y: u32,
+/// }
+/// ```
+///
+/// So are packed structs, since their fields may not be aligned.
+///
+/// ```compile_fail
+/// # use shared_data::SharedMemCast;
+/// #[derive(SharedMemCast)]
+/// #[repr(C, packed)]
+/// struct Point {
+///     x: u8,
+///     y: u32,
+/// }
+/// ```
+#[proc_macro_derive(SharedMemCast)]
+pub fn derive_shared_mem_cast(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    derive_unsafe_trait(input, parse_quote!(::shared_data::SharedMemCast))
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Derives `SharedMemRef` for a `#[repr(C)]` struct whose fields are all `SharedMemRef`.
+///
+/// ```
+/// # use shared_data::SharedMemCast;
+/// # use shared_data::SharedMemRef;
+/// # use std::sync::atomic::AtomicUsize;
+/// #[derive(SharedMemCast, SharedMemRef)]
+/// #[repr(C)]
+/// struct Counter {
+///     count: AtomicUsize,
+/// }
+/// ```
+///
+/// Packed structs are rejected, since references to their fields may not be aligned.
+///
+/// ```compile_fail
+/// # use shared_data::SharedMemRef;
+/// #[derive(SharedMemRef)]
+/// #[repr(C, packed(2))]
+/// struct Empty {
+///     unit: (),
+/// }
+/// ```
+#[proc_macro_derive(SharedMemRef)]
+pub fn derive_shared_mem_ref(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    derive_unsafe_trait(input, parse_quote!(::shared_data::SharedMemRef))
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+fn derive_unsafe_trait(mut input: DeriveInput, bound: Path) -> Result<TokenStream, Error> {
+    let fields = match input.data {
+        Data::Struct(ref data) => &data.fields,
+        _ => {
+            return Err(Error::new(
+                input.span(),
+                "shared memory traits can only be derived for structs",
+            ))
+        }
+    };
+    let repr = reprs(&input);
+    if !repr
+        .iter()
+        .any(|path| path.is_ident("C") || path.is_ident("transparent"))
+    {
+        return Err(Error::new(
+            input.ident.span(),
+            "shared memory traits can only be derived for #[repr(C)] structs",
+        ));
+    }
+    if let Some(packed) = repr.iter().find(|path| path.is_ident("packed")) {
+        return Err(Error::new(
+            packed.span(),
+            "shared memory traits can't be derived for #[repr(packed)] structs",
+        ));
+    }
+
+    // Type parameters are required to implement the trait, as fields may depend on them.
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#bound));
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let assertions = fields.iter().map(|field| {
+        let ty = &field.ty;
+        quote_spanned!(ty.span()=> assert_field::<#ty>();)
+    });
+
+    Ok(quote! {
+        #[allow(unsafe_code)]
+        unsafe impl #impl_generics #bound for #name #ty_generics #where_clause {}
+
+        const _: () = {
+            fn assert_field<T: ?Sized + #bound>() {}
+            #[allow(dead_code)]
+            fn assert_fields #impl_generics () #where_clause {
+                #(#assertions)*
+            }
+        };
+    })
+}
+
+// The representations in the `#[repr(...)]` attributes, such as `C`, `packed` or `packed(2)`.
+fn reprs(input: &DeriveInput) -> Vec<Path> {
+    input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("repr"))
+        .filter_map(|attr| attr.parse_meta().ok())
+        .flat_map(|meta| match meta {
+            Meta::List(list) => list
+                .nested
+                .into_iter()
+                .filter_map(|nested| match nested {
+                    NestedMeta::Meta(Meta::Path(path)) => Some(path),
+                    NestedMeta::Meta(Meta::List(list)) => Some(list.path),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        })
+        .collect()
+}
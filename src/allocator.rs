@@ -10,106 +10,266 @@ use num_traits::FromPrimitive;
 use num_traits::ToPrimitive;
 use owning_ref::BoxRef;
 use owning_ref::OwningRef;
-use shared_memory::EventState;
-use shared_memory::EventType;
 use shared_memory::SharedMem;
 use shared_memory::SharedMemConf;
-use shared_memory::Timeout;
+use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::mem;
 use std::ops::Deref;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::Mutex;
 
-use crate::AtomicSharedAddress;
+use crate::shared_address_range::GENERATION_BITS;
+use crate::shared_address_range::MAX_SHMEM_SIZE;
+use crate::shared_header::type_fingerprint;
+use crate::shared_root::lookup_root;
+use crate::shared_root::publish_root;
+use crate::shared_root::unpublish_root;
+use crate::shared_root::SharedRoot;
+use crate::shmem_table::ShmemTable;
+use crate::shmem_table::SHMEMS_PER_TABLE;
+use crate::size_class::NUM_SIZE_CLASSES;
+use crate::slab_state::SlabState;
+use crate::slab_state::MAX_SLAB_OBJECTS;
+use crate::unsafe_code::LocalShmem;
+use crate::unsafe_code::MappedShmem;
+use crate::unsafe_code::ALLOCATORS;
+use crate::AllocatorId;
 use crate::AtomicSharedAddressRange;
+use crate::FreeListEnd;
+use crate::FreeListInspection;
 use crate::ObjectOffset;
-use crate::ObjectSize;
-use crate::SharedAddress;
+use crate::SegmentStats;
 use crate::SharedAddressRange;
+use crate::SharedDataError;
+use crate::SharedMemCast;
+use crate::SharedRc;
 use crate::ShmemId;
+use crate::ShmemInspection;
 use crate::ShmemName;
+use crate::ShmemProblem;
+use crate::ShmemReport;
+use crate::ShmemStats;
+use crate::SizeClass;
+use crate::SizeClassStats;
+use crate::SlabInspection;
 use crate::SyncSharedMem;
 use crate::Volatile;
 
 #[cfg(feature = "no-panic")]
 use no_panic::no_panic;
 
-// We double the size of the shared blocks each time we allocate one,
-// so we will run out of memory a long time before we run out of shared
-// memory blocks.
-const MAX_SHMEMS: usize = 64;
-const MIN_OBJECT_SIZE: usize = 8;
+// Shared memory ids are represented using a u16, and segments are tracked
+// in tables which are linked together as they fill up.
+const MAX_SHMEMS: usize = 1 << 16;
+const MAX_TABLES: usize = MAX_SHMEMS / SHMEMS_PER_TABLE;
 
-// Object sizes are represented using a u8 (byte n represents size 2^n)
-// so there are at most 256 of them.
-const NUM_OBJECT_SIZES: usize = 256;
+// Object offsets are represented using 40 bits, so objects are at most 1TiB.
+// Slices are at most `isize::MAX` bytes, which is smaller on 32-bit platforms.
+fn max_object_size() -> usize {
+    usize::try_from(MAX_SHMEM_SIZE).map_or(isize::MAX as usize, |size| {
+        usize::min(size, isize::MAX as usize)
+    })
+}
+
+// Objects which fill a shared memory segment of their own are rounded up to this.
+const PAGE_SIZE: usize = 4096;
+
+// Smaller objects are allocated from slabs of about this size,
+// as long as they hold at least a few objects.
+const SLAB_SIZE: usize = 256 << 10;
+const MIN_SLAB_OBJECTS: usize = 4;
+
+// The number of objects of the given size in each slab.
+fn slab_capacity(object_size: usize) -> usize {
+    (SLAB_SIZE / object_size).clamp(MIN_SLAB_OBJECTS, MAX_SLAB_OBJECTS)
+}
+
+// Every object starts with a header.
+// This is 16 bytes, so that the data after it is 16-byte aligned.
+const OBJECT_HEADER_SIZE: usize = mem::size_of::<ObjectHeader>();
+
+// The number of objects that can be published by name.
+const MAX_ROOTS: usize = 64;
+
+/// The header at the start of every object.
+#[repr(C)]
+pub(crate) struct ObjectHeader {
+    // Bumped each time the object is freed, so stale addresses can be detected.
+    generation: AtomicU64,
+    // The fingerprint of the type stored in the object, set by containers such as `SharedBox`,
+    // so an address received from another process can be checked before it is used.
+    type_tag: AtomicU64,
+}
+
+// The type tag of objects allocated with `alloc_bytes`.
+const UNTAGGED: u64 = 0;
 
 pub(crate) struct ShmemMetadata {
+    id: AllocatorId,
     name: Volatile<ShmemName>,
     num_shmems: AtomicUsize,
-    shmem_used: [AtomicBool; MAX_SHMEMS],
-    shmem_names: [Volatile<ShmemName>; MAX_SHMEMS],
-    unused: AtomicSharedAddress,
-    free_lists: [AtomicSharedAddressRange; NUM_OBJECT_SIZES],
+    // The first table of shared memory segments.
+    shmems: ShmemTable,
+    roots: [SharedRoot; MAX_ROOTS],
+    // Statistics, which are updated by every process using the heap.
+    bytes_allocated: AtomicU64,
+    free_list_lengths: [AtomicUsize; NUM_SIZE_CLASSES],
+    live_objects: [AtomicUsize; NUM_SIZE_CLASSES],
 }
 
 impl ShmemMetadata {
     fn new(name: ShmemName) -> ShmemMetadata {
         ShmemMetadata {
+            id: AllocatorId::random(),
             name: Volatile::new(name),
             num_shmems: AtomicUsize::new(0),
-            shmem_used: array![AtomicBool::new(false); MAX_SHMEMS],
-            shmem_names: array![Volatile::new(ShmemName::default()); MAX_SHMEMS],
-            unused: AtomicSharedAddress::default(),
-            free_lists: array![AtomicSharedAddressRange::default(); NUM_OBJECT_SIZES],
+            shmems: ShmemTable::new(),
+            roots: array![SharedRoot::new(); MAX_ROOTS],
+            bytes_allocated: AtomicU64::new(0),
+            free_list_lengths: array![AtomicUsize::new(0); NUM_SIZE_CLASSES],
+            live_objects: array![AtomicUsize::new(0); NUM_SIZE_CLASSES],
+        }
+    }
+}
+
+// This process's view of a table of shared memory segments.
+struct LocalShmemTable {
+    // The mapping of the table, or `None` for the table in the metadata.
+    table: Option<BoxRef<SyncSharedMem, ShmemTable>>,
+    // The mappings of the segments, which are replaced when a slot is reused.
+    shmems: [LocalShmem; SHMEMS_PER_TABLE],
+    // For segments created by this process, one more than their incarnation,
+    // so they can be unlinked when the allocator is closed.
+    created: [AtomicU64; SHMEMS_PER_TABLE],
+}
+
+impl LocalShmemTable {
+    fn new(table: Option<BoxRef<SyncSharedMem, ShmemTable>>) -> LocalShmemTable {
+        LocalShmemTable {
+            table,
+            shmems: array![LocalShmem::new(); SHMEMS_PER_TABLE],
+            created: array![AtomicU64::new(0); SHMEMS_PER_TABLE],
         }
     }
 }
 
+/// An allocator for a heap of shared memory.
+///
+/// Allocators live until they are closed once they are created or opened,
+/// so that containers can find the allocator they were allocated in.
+///
+/// Objects are allocated by size class, including their header. Objects of up to 2MiB
+/// are allocated from slabs, which are shared memory segments holding objects of a single
+/// class. Each slab has its own free list, and objects are reused from there, or else
+/// bumped off the unused memory at the end of the slab. Larger objects are given
+/// shared memory of their own, rounded up to a page.
+///
+/// Once every object in a slab has been freed, the slab is released, as is shared memory
+/// holding a single large object when that object is freed. Released shared memory
+/// is unlinked, and each process replaces its mapping by zero pages once it notices,
+/// so the OS frees the shared memory when no process has it mapped any more.
+/// Its slot in the segment table is then reused for new shared memory.
+/// On platforms other than Unix, shared memory is only returned to the OS
+/// when the heap is closed.
 pub struct ShmemAllocator {
-    // Locally we store the mmap'd memory slices
-    shmems: [AtomSetOnce<Box<SyncSharedMem>>; MAX_SHMEMS],
+    // Locally we store the mmap'd tables and memory slices
+    tables: [AtomSetOnce<Box<LocalShmemTable>>; MAX_TABLES],
     // The metadata is stored in shared memory
     metadata_shmem: BoxRef<SyncSharedMem, ShmemMetadata>,
+    // Set for allocators which are only used for inspection, whose shared memory is mapped read-only
+    read_only: bool,
 }
 
 impl ShmemAllocator {
-    pub fn from_shmem(shmem: SyncSharedMem) -> Option<ShmemAllocator> {
-        let metadata_shmem = OwningRef::new(Box::new(shmem))
-            .try_map(|bytes| {
-                Volatile::<ShmemMetadata>::from_volatile_bytes(bytes)
-                    .map(|metadata| metadata.deref())
-                    .ok_or(())
-            })
-            .ok()?;
-        Some(ShmemAllocator {
-            shmems: array![AtomSetOnce::empty(); MAX_SHMEMS],
+    /// The size of the header at the start of every object, which counts towards its size class.
+    pub const OBJECT_HEADER_SIZE: usize = OBJECT_HEADER_SIZE;
+
+    pub(crate) fn from_shmem(shmem: SyncSharedMem) -> Result<ShmemAllocator, SharedDataError> {
+        let metadata_shmem = OwningRef::new(Box::new(shmem)).try_map(|bytes| {
+            Volatile::<ShmemMetadata>::from_volatile_bytes(bytes)
+                .map(|metadata| metadata.deref())
+                .ok_or(SharedDataError::TooSmall {
+                    size: bytes.len(),
+                    required: mem::size_of::<ShmemMetadata>(),
+                })
+        })?;
+        let tables = array![AtomSetOnce::empty(); MAX_TABLES];
+        tables[0].set_if_none(Box::new(LocalShmemTable::new(None)));
+        Ok(ShmemAllocator {
+            tables,
             metadata_shmem,
+            read_only: false,
         })
     }
 
-    pub fn create() -> Option<ShmemAllocator> {
+    /// Creates a new heap of shared memory.
+    pub fn create() -> Result<&'static ShmemAllocator, SharedDataError> {
         let size = mem::size_of::<ShmemMetadata>();
         let shmem = SharedMemConf::new()
             .set_size(size)
-            .add_event(EventType::Auto)
-            .ok()?
             .create()
-            .ok()?;
-        let shmem_name = ShmemName::from_str(shmem.get_os_path())?;
+            .map_err(SharedDataError::shmem)?;
+        let shmem_name = ShmemName::from_str(shmem.get_os_path())
+            .ok_or_else(|| SharedDataError::NameTooLong(String::from(shmem.get_os_path())))?;
         let shmem = SyncSharedMem::from_shmem(shmem);
         let metadata = ShmemMetadata::new(shmem_name);
-        let volatile_metadata = Volatile::<ShmemMetadata>::from_volatile_bytes(&*shmem)?;
+        let volatile_metadata = Volatile::<ShmemMetadata>::from_volatile_bytes(&*shmem).ok_or(
+            SharedDataError::TooSmall {
+                size: shmem.len(),
+                required: size,
+            },
+        )?;
         volatile_metadata.write_volatile(metadata);
-        ShmemAllocator::from_shmem(shmem)
+        ALLOCATORS.register(ShmemAllocator::from_shmem(shmem)?)
     }
 
-    pub fn open(name: &str) -> Option<ShmemAllocator> {
-        let shmem = SharedMem::open(name).ok()?;
+    /// Opens an existing heap of shared memory by name.
+    ///
+    /// If this process already has the heap open, returns the same allocator.
+    pub fn open(name: &str) -> Result<&'static ShmemAllocator, SharedDataError> {
+        ALLOCATORS.find_or_register(name, || {
+            let shmem = SharedMem::open(name).map_err(SharedDataError::shmem)?;
+            ShmemAllocator::from_shmem(SyncSharedMem::from_shmem(shmem))
+        })
+    }
+
+    /// Dumps the metadata of an existing heap of shared memory by name.
+    ///
+    /// The heap isn't registered with this process, and on Linux its shared memory
+    /// is mapped read-only, so this can't change a heap that other processes are using.
+    pub fn inspect_by_name(name: &str) -> Result<ShmemInspection, SharedDataError> {
+        let shmem = SharedMem::open(name).map_err(SharedDataError::shmem)?;
         let shmem = SyncSharedMem::from_shmem(shmem);
-        ShmemAllocator::from_shmem(shmem)
+        shmem.make_read_only()?;
+        let mut alloc = ShmemAllocator::from_shmem(shmem)?;
+        alloc.read_only = true;
+        Ok(alloc.inspect())
+    }
+
+    // Maps shared memory owned by the heap, read-only if this allocator is.
+    fn open_shmem(&self, name: ShmemName) -> Result<SyncSharedMem, SharedDataError> {
+        let shmem = SharedMem::open(name.as_str()).map_err(SharedDataError::shmem)?;
+        let shmem = SyncSharedMem::from_shmem(shmem);
+        if self.read_only {
+            shmem.make_read_only()?;
+        }
+        Ok(shmem)
+    }
+
+    // The same, for a segment, which is at least the given size.
+    fn open_segment(&self, name: ShmemName, size: usize) -> Result<SyncSharedMem, SharedDataError> {
+        let shmem = SyncSharedMem::open(name, size)?;
+        if self.read_only {
+            shmem.make_read_only()?;
+        }
+        Ok(shmem)
+    }
+
+    pub(crate) fn from_id(id: AllocatorId) -> Result<&'static ShmemAllocator, SharedDataError> {
+        ALLOCATORS.find(id).ok_or(SharedDataError::UnknownAllocator)
     }
 
     // For some reason no-pqanic complains about this function
@@ -117,8 +277,14 @@ impl ShmemAllocator {
         &*self.metadata_shmem
     }
 
-    pub fn name(&self) -> ShmemName {
-        self.metadata().name.read_volatile()
+    pub(crate) fn id(&self) -> AllocatorId {
+        self.metadata().id
+    }
+
+    /// The name of the shared memory used by this allocator,
+    /// which can be passed to `open` in another process.
+    pub fn name(&self) -> String {
+        String::from(self.metadata().name.read_volatile().as_str())
     }
 
     #[cfg_attr(feature = "no-panic", no_panic)]
@@ -126,170 +292,910 @@ impl ShmemAllocator {
         self.metadata().num_shmems.load(Ordering::SeqCst)
     }
 
-    fn get_shmem_name(&self, shmem_id: ShmemId) -> Option<ShmemName> {
+    // This process's view of a table, mapping it if necessary.
+    fn local_table(&self, table_index: usize) -> Option<&LocalShmemTable> {
+        let slot = self.tables.get(table_index)?;
+        if let Some(local) = slot.get() {
+            return Some(local);
+        }
+        // Each table is linked from the one before, so we map them in order.
+        // This is a loop rather than recursion, since there can be a thousand tables.
+        for index in 1..=table_index {
+            let slot = &self.tables[index];
+            if slot.get().is_some() {
+                continue;
+            }
+            let name = self.table(index - 1)?.next_name()?;
+            let table = map_table(self.open_shmem(name).ok()?).ok()?;
+            // If another thread beat us to it, we use their mapping.
+            slot.set_if_none(Box::new(LocalShmemTable::new(Some(table))));
+        }
+        slot.get()
+    }
+
+    fn table(&self, table_index: usize) -> Option<&ShmemTable> {
+        if table_index == 0 {
+            return Some(&self.metadata().shmems);
+        }
+        self.local_table(table_index)?.table.as_deref()
+    }
+
+    // The tables which have been created so far.
+    fn tables(&self) -> impl Iterator<Item = (usize, &ShmemTable)> {
+        (0..MAX_TABLES).map_while(move |table_index| Some((table_index, self.table(table_index)?)))
+    }
+
+    // Links a new table after the given one.
+    fn create_table(&self, table_index: usize) -> Result<ShmemName, SharedDataError> {
+        let slot = self
+            .tables
+            .get(table_index)
+            .ok_or(SharedDataError::OutOfSegments)?;
+        let size = mem::size_of::<ShmemTable>();
+        let shmem = SharedMemConf::new()
+            .set_size(size)
+            .create()
+            .map_err(SharedDataError::shmem)?;
+        let shmem_name = ShmemName::from_str(shmem.get_os_path())
+            .ok_or_else(|| SharedDataError::NameTooLong(String::from(shmem.get_os_path())))?;
+        let shmem = SyncSharedMem::from_shmem(shmem);
+        Volatile::<ShmemTable>::from_volatile_bytes(&*shmem)
+            .ok_or(SharedDataError::TooSmall {
+                size: shmem.len(),
+                required: size,
+            })?
+            .write_volatile(ShmemTable::new());
+        debug!("Created shmem table {}", table_index);
+        // Nobody else can map the table until we return its name.
+        slot.set_if_none(Box::new(LocalShmemTable::new(Some(map_table(shmem)?))));
+        Ok(shmem_name)
+    }
+
+    // The table entry and the local mapping for a shared memory id.
+    fn shmem_entry(&self, shmem_id: ShmemId) -> Option<(&ShmemTable, &LocalShmem, usize)> {
         let index = shmem_id.to_usize()?;
-        if index > self.get_num_shmems() {
-            None
-        } else if !self
-            .metadata()
-            .shmem_used
-            .get(index)?
-            .load(Ordering::SeqCst)
-        {
-            None
+        let table_index = index / SHMEMS_PER_TABLE;
+        let index = index % SHMEMS_PER_TABLE;
+        let table = self.table(table_index)?;
+        let local = self.local_table(table_index)?;
+        Some((table, local.shmems.get(index)?, index))
+    }
+
+    fn get_shmem_name(&self, shmem_id: ShmemId) -> Option<ShmemName> {
+        let (table, _, index) = self.shmem_entry(shmem_id)?;
+        if table.used.get(index)?.load(Ordering::SeqCst) {
+            Some(table.names.get(index)?.read_volatile())
         } else {
-            Some(self.metadata().shmem_names.get(index)?.read_volatile())
+            None
         }
     }
 
     // I'd like to be able to mark this as `no_panic` but unfortunately
     // the shared memory crate can panic when opening a shared memory file.
-    fn get_shmem(&self, shmem_id: ShmemId) -> Option<&SyncSharedMem> {
-        let index = shmem_id.to_usize()?;
-        let atomic_shmem = self.shmems.get(index)?;
-        if let Some(shmem) = atomic_shmem.get() {
-            return Some(shmem);
+    fn get_shmem(&self, address: SharedAddressRange) -> Result<&SyncSharedMem, SharedDataError> {
+        let shmem_id = address.shmem_id();
+        let (table, local, index) = self
+            .shmem_entry(shmem_id)
+            .ok_or(SharedDataError::UnknownSegment(address))?;
+        // If the shared memory has been released, our mapping is out of date,
+        // and if the slot has been reused, we map the new shared memory.
+        let incarnation = table.incarnations[index].load(Ordering::SeqCst);
+        let mapped = local.get();
+        if let Some(mapped) = mapped.filter(|mapped| mapped.incarnation == incarnation) {
+            return Ok(&mapped.shmem);
+        }
+        let shmem_name = self
+            .get_shmem_name(shmem_id)
+            .ok_or(SharedDataError::UnknownSegment(address))?;
+        let size = table.sizes[index].load(Ordering::SeqCst);
+        let shmem = self.open_segment(shmem_name, size)?;
+        // The slot may have been reused while we were opening it.
+        if table.incarnations[index].load(Ordering::SeqCst) != incarnation {
+            return Err(SharedDataError::UnknownSegment(address));
         }
-        let shmem_name = self.get_shmem_name(shmem_id)?;
-        let new_shmem = SharedMem::open(shmem_name.as_str()).ok()?;
-        let new_boxed_shmem = Box::new(SyncSharedMem::from_shmem(new_shmem));
-        atomic_shmem.set_if_none(new_boxed_shmem);
-        atomic_shmem.get()
+        // If another thread beat us to it, we use their mapping.
+        local
+            .replace(mapped, MappedShmem::new(incarnation, shmem))
+            .filter(|mapped| mapped.incarnation == incarnation)
+            .map(|mapped| &mapped.shmem)
+            .ok_or(SharedDataError::UnknownSegment(address))
     }
 
-    // I'd like to be able to mark this as `no_panic` but unfortunately
-    // the shared memory crate can panic when creating a shared memory file.
-    fn alloc_shmem(&self, size: usize) -> Option<ShmemId> {
-        let shmem = SharedMemConf::new().set_size(size).create().ok()?;
-        let shmem_name = ShmemName::from_str(shmem.get_os_path())?;
-        let boxed_shmem = Box::new(SyncSharedMem::from_shmem(shmem));
-        let mut index = self.metadata().num_shmems.load(Ordering::Relaxed);
-        while self
-            .metadata()
-            .shmem_used
-            .get(index)?
-            .swap(true, Ordering::SeqCst)
-        {
-            if MAX_SHMEMS <= index {
-                return None;
-            } else {
-                index += 1;
+    // The size of a shared memory segment which is in use.
+    fn get_shmem_size(&self, shmem_id: ShmemId) -> Option<usize> {
+        self.get_shmem_name(shmem_id)?;
+        let (table, _, index) = self.shmem_entry(shmem_id)?;
+        Some(table.sizes.get(index)?.load(Ordering::SeqCst))
+    }
+
+    // Creates shared memory, and puts it in the first unused slot.
+    fn alloc_shmem(&self, size: usize) -> Result<ShmemId, SharedDataError> {
+        self.release_stale_mappings();
+        let (shmem, shmem_name) = SyncSharedMem::create(size)?;
+        // Find an unused slot, adding a table if they are all in use.
+        let mut table_index = 0;
+        let result = loop {
+            let table = match self.table(table_index) {
+                Some(table) => table,
+                None => break Err(SharedDataError::OutOfSegments),
+            };
+            let index = table
+                .used
+                .iter()
+                .position(|used| !used.swap(true, Ordering::SeqCst));
+            if let Some(index) = index {
+                break Ok((table, index));
             }
-        }
+            table_index += 1;
+            if table_index >= MAX_TABLES {
+                break Err(SharedDataError::OutOfSegments);
+            }
+            if let Err(err) = table.link_next(|| self.create_table(table_index)) {
+                break Err(err);
+            }
+        };
+        let (table, index) = match result {
+            Ok(entry) => entry,
+            Err(err) => {
+                SyncSharedMem::unlink(shmem_name);
+                return Err(err);
+            }
+        };
+        let shmem_id = ShmemId::from_usize(table_index * SHMEMS_PER_TABLE + index)
+            .ok_or(SharedDataError::OutOfSegments)?;
         debug!(
-            "Allocated shmem {} of size {} (requested {:?})",
-            index,
-            boxed_shmem.len(),
-            size
+            "Allocated shmem {:?} of size {} (requested {:?}, {} already in use)",
+            shmem_id,
+            shmem.len(),
+            size,
+            self.get_num_shmems(),
         );
-        self.metadata()
-            .shmem_names
-            .get(index)?
-            .write_volatile(shmem_name);
-        self.shmems.get(index)?.set_if_none(boxed_shmem);
+        table.sizes[index].store(shmem.len(), Ordering::SeqCst);
+        table.names[index].write_volatile(shmem_name);
+        let incarnation = table.incarnations[index].load(Ordering::SeqCst);
+        if let Some(local) = self.local_table(table_index) {
+            local.created[index].store(incarnation + 1, Ordering::SeqCst);
+            let slot = &local.shmems[index];
+            slot.replace(slot.get(), MappedShmem::new(incarnation, shmem));
+        }
         self.metadata().num_shmems.fetch_add(1, Ordering::SeqCst);
-        ShmemId::from_usize(index)
+        Ok(shmem_id)
     }
 
-    #[cfg_attr(feature = "no-panic", no_panic)]
-    fn free_shmem(&self, _shmem_id: ShmemId) {
-        // TODO
+    // Releases shared memory once nothing is allocated in it, so its slot can be reused.
+    // Objects in the next shared memory in the slot start at `next_generation` or later.
+    //
+    // Other threads may still have slices of the shared memory, so rather than unmapping it,
+    // we unlink it and replace our mapping by zero pages. Other processes do the same
+    // once they notice that the slot's incarnation has changed.
+    fn free_shmem(&self, shmem_id: ShmemId, next_generation: u64) -> Option<()> {
+        let (table, local, index) = self.shmem_entry(shmem_id)?;
+        let shmem_name = table.names[index].read_volatile();
+        let incarnation = table.incarnations[index].fetch_add(1, Ordering::SeqCst);
+        table.generations[index].fetch_max(next_generation, Ordering::SeqCst);
+        SyncSharedMem::unlink(shmem_name);
+        if let Some(mapped) = local
+            .get()
+            .filter(|mapped| mapped.incarnation == incarnation)
+        {
+            mapped.release();
+        }
+        debug!("Freed shmem {:?}", shmem_id);
+        self.metadata().num_shmems.fetch_sub(1, Ordering::SeqCst);
+        table.used[index].store(false, Ordering::SeqCst);
+        self.release_stale_mappings();
+        Some(())
     }
 
-    pub fn get_bytes(&self, address: SharedAddressRange) -> Option<&[Volatile<u8>]> {
-        let shmem = self.get_shmem(address.shmem_id())?;
-        let object_offset = address.object_offset().to_usize()?;
-        let object_end = address.object_end()?.to_usize()?;
-        if object_end > shmem.len() {
-            None
-        } else {
-            Some(&shmem[object_offset..object_end])
+    // Releases our mappings of shared memory which other processes have released,
+    // so that we don't keep it alive.
+    fn release_stale_mappings(&self) {
+        for (table_index, table) in self.tables() {
+            let local = match self.local_table(table_index) {
+                Some(local) => local,
+                None => continue,
+            };
+            for (index, slot) in local.shmems.iter().enumerate() {
+                let incarnation = table.incarnations[index].load(Ordering::SeqCst);
+                if let Some(mapped) = slot
+                    .get()
+                    .filter(|mapped| mapped.incarnation != incarnation)
+                {
+                    mapped.release();
+                }
+            }
         }
     }
 
-    pub fn alloc_bytes(&self, size: usize) -> Option<SharedAddressRange> {
-        let object_size = ObjectSize::ceil(usize::max(MIN_OBJECT_SIZE, size));
-        loop {
-            if let Some(result) = self.unfree_bytes(object_size) {
-                debug!("Unfreed {:?}", result);
-                return Some(result);
-            }
-            if let Some(result) = self
-                .metadata()
-                .unused
-                .fetch_add(object_size, Ordering::SeqCst)
-            {
-                debug!("Allocated {:?}", result);
-                return Some(result);
-            }
-            let old_unused = self.metadata().unused.load(Ordering::SeqCst);
-            let old_shmem_size = self
-                .get_shmem(old_unused.shmem_id())
-                .map(|shmem| shmem.len())
-                .unwrap_or(0);
-            let new_shmem_size = ObjectSize::max(object_size, ObjectSize::ceil(old_shmem_size + 1));
-            let new_shmem_id = self.alloc_shmem(new_shmem_size.to_usize()?)?;
-            let object_offset = ObjectOffset::from_u64(0)?;
-            let new_unused = SharedAddress::new(new_shmem_id, new_shmem_size, object_offset);
-            if old_unused
-                != self
-                    .metadata()
-                    .unused
-                    .compare_and_swap(old_unused, new_unused, Ordering::SeqCst)
-            {
-                self.free_shmem(new_shmem_id);
+    // The header of the object at a shared address, and the bytes after it.
+    fn get_object_unchecked(
+        &self,
+        address: SharedAddressRange,
+    ) -> Result<(&ObjectHeader, &[Volatile<u8>]), SharedDataError> {
+        let shmem = self.get_shmem(address)?;
+        let out_of_bounds = SharedDataError::OutOfBounds(address);
+        let object_offset = address.object_offset().to_usize().ok_or(out_of_bounds)?;
+        let object_end = match address.object_end() {
+            None if address.object_size().is_whole_shmem() => Some(shmem.len()),
+            object_end => object_end.and_then(|object_end| object_end.to_usize()),
+        };
+        let object_end = object_end
+            .filter(|object_end| *object_end <= shmem.len())
+            .ok_or(SharedDataError::OutOfBounds(address))?;
+        let bytes = &shmem[object_offset..object_end];
+        let header: &ObjectHeader =
+            Volatile::from_volatile_bytes(bytes).ok_or(SharedDataError::OutOfBounds(address))?;
+        Ok((header, &bytes[OBJECT_HEADER_SIZE..]))
+    }
+
+    // The same, checking that the address is not stale.
+    fn get_object(
+        &self,
+        address: SharedAddressRange,
+    ) -> Result<(&ObjectHeader, &[Volatile<u8>]), SharedDataError> {
+        let (header, bytes) = self.get_object_unchecked(address)?;
+        let generation = self.get_generation(address, header)?;
+        if generation_bits(generation.load(Ordering::SeqCst)) != address.generation() {
+            return Err(SharedDataError::StaleAddress(address));
+        }
+        Ok((header, bytes))
+    }
+
+    // Objects which fill a shared memory segment of their own keep their generation
+    // in the segment table, since their header is lost when the segment is freed.
+    fn get_generation<'a>(
+        &'a self,
+        address: SharedAddressRange,
+        header: &'a ObjectHeader,
+    ) -> Result<&'a AtomicU64, SharedDataError> {
+        if !address.object_size().is_whole_shmem() {
+            return Ok(&header.generation);
+        }
+        let (table, _, index) = self
+            .shmem_entry(address.shmem_id())
+            .ok_or(SharedDataError::UnknownSegment(address))?;
+        Ok(&table.generations[index])
+    }
+
+    // Free lists don't store generations, so we read them from the object.
+    fn with_current_generation(
+        &self,
+        address: SharedAddressRange,
+    ) -> Result<SharedAddressRange, SharedDataError> {
+        let (header, _) = self.get_object_unchecked(address)?;
+        Ok(address.with_generation(generation_bits(header.generation.load(Ordering::SeqCst))))
+    }
+
+    /// Accesses the bytes at a shared address.
+    ///
+    /// Returns an error if the address is stale, because the object has been freed.
+    /// Addresses only keep the low 7 bits of the object's generation, so an address
+    /// whose memory has been freed and reused a multiple of 128 times is not detected.
+    pub fn get_bytes(
+        &self,
+        address: SharedAddressRange,
+    ) -> Result<&[Volatile<u8>], SharedDataError> {
+        self.get_object(address).map(|(_, bytes)| bytes)
+    }
+
+    /// Accesses the bytes at a shared address, checking that they were allocated
+    /// by `alloc_tagged` with the same type tag.
+    pub(crate) fn get_tagged_bytes(
+        &self,
+        address: SharedAddressRange,
+        type_tag: u64,
+    ) -> Result<&[Volatile<u8>], SharedDataError> {
+        let (header, bytes) = self.get_object(address)?;
+        if header.type_tag.load(Ordering::SeqCst) != type_tag {
+            return Err(SharedDataError::WrongType(address));
+        }
+        Ok(bytes)
+    }
+
+    /// Allocates bytes in shared memory.
+    pub fn alloc_bytes(&self, size: usize) -> Result<SharedAddressRange, SharedDataError> {
+        self.alloc_tagged(size, UNTAGGED)
+    }
+
+    /// Allocates bytes in shared memory, tagged with the fingerprint of the type they will hold.
+    ///
+    /// The tag is kept in the object header, so it costs no extra space.
+    pub(crate) fn alloc_tagged(
+        &self,
+        size: usize,
+        type_tag: u64,
+    ) -> Result<SharedAddressRange, SharedDataError> {
+        let address = self.alloc_object(size)?;
+        let (header, _) = self.get_object(address)?;
+        header.type_tag.store(type_tag, Ordering::SeqCst);
+        Ok(address)
+    }
+
+    // Allocates an object by size class, from a slab with room for it or a new slab,
+    // or in shared memory of its own if it is large.
+    fn alloc_object(&self, size: usize) -> Result<SharedAddressRange, SharedDataError> {
+        let object_bytes = size
+            .checked_add(OBJECT_HEADER_SIZE)
+            .filter(|object_bytes| *object_bytes <= max_object_size())
+            .ok_or(SharedDataError::TooLarge(size))?;
+        let object_size = SizeClass::ceil(object_bytes);
+        if object_size.is_whole_shmem() {
+            return self.alloc_whole_shmem(object_bytes);
+        }
+        if let Some(result) = self.alloc_from_slabs(object_size) {
+            return Ok(result);
+        }
+        self.alloc_slab(object_size)
+    }
+
+    // Allocates an object from any slab of the given size class which has room for it.
+    fn alloc_from_slabs(&self, object_size: SizeClass) -> Option<SharedAddressRange> {
+        for (table_index, table) in self.tables() {
+            let partial = &table.partial[object_size.0 as usize];
+            let mut slabs = partial.load(Ordering::SeqCst);
+            while slabs != 0 {
+                let index = slabs.trailing_zeros() as usize;
+                slabs &= slabs - 1;
+                let shmem_id = ShmemId::from_usize(table_index * SHMEMS_PER_TABLE + index)?;
+                if let Some(result) = self.alloc_from_slab(table, index, shmem_id, object_size) {
+                    return Some(result);
+                }
+                // The slab is full, so take it off the list, unless an object has been freed
+                // since we looked, in which case whoever freed it may have missed our update.
+                partial.fetch_and(!(1 << index), Ordering::SeqCst);
+                if self.slab_has_room(table, index, object_size) {
+                    partial.fetch_or(1 << index, Ordering::SeqCst);
+                }
             }
         }
+        None
     }
 
-    #[cfg_attr(feature = "no-panic", no_panic)]
-    fn unfree_bytes(&self, object_size: ObjectSize) -> Option<SharedAddressRange> {
-        let free_list = &self.metadata().free_lists[object_size.0 as usize];
+    // Allocates an object from a slab, from its free list or its unused memory.
+    fn alloc_from_slab(
+        &self,
+        table: &ShmemTable,
+        index: usize,
+        shmem_id: ShmemId,
+        object_size: SizeClass,
+    ) -> Option<SharedAddressRange> {
+        let capacity = slab_capacity(object_size.to_usize()?);
+        let slab = &table.slabs[index];
+        let metadata = self.metadata();
         loop {
-            let head = free_list.load(Ordering::SeqCst);
-            if head == SharedAddressRange::null() {
+            let state = slab.load(Ordering::SeqCst);
+            // The class is read after the state, so if the slab has been reused
+            // for another class, either the class is wrong or the update fails.
+            if state.is_released()
+                || table.size_classes[index].load(Ordering::SeqCst) != object_size.0
+            {
                 return None;
             }
-            let bytes = self.get_bytes(head)?;
-            let tail: &AtomicSharedAddressRange = Volatile::from_volatile_bytes(bytes)?;
-            let tail: SharedAddressRange = tail.load(Ordering::SeqCst);
-            if head == free_list.compare_and_swap(head, tail, Ordering::SeqCst) {
-                return Some(head);
+            if let Some(head) = state.head() {
+                let address = slab_address(shmem_id, object_size, head)?;
+                let (header, bytes) = self.get_object_unchecked(address).ok()?;
+                let next: &AtomicSharedAddressRange = Volatile::from_volatile_bytes(bytes)?;
+                // The next address may be stale, if another process allocated the head
+                // in the meantime, but in that case the state's tag has changed,
+                // and the update fails.
+                let next = Some(next.load(Ordering::SeqCst))
+                    .filter(|next| *next != SharedAddressRange::null())
+                    .and_then(|next| slab_index(next, shmem_id, object_size, state.bumped()));
+                if slab.compare_and_set(state, state.pop(next)) {
+                    let generation = header.generation.load(Ordering::SeqCst);
+                    let result = address.with_generation(generation_bits(generation));
+                    debug!("Unfreed {:?}", result);
+                    metadata.free_list_lengths[object_size.0 as usize]
+                        .fetch_sub(1, Ordering::SeqCst);
+                    metadata.live_objects[object_size.0 as usize].fetch_add(1, Ordering::SeqCst);
+                    return Some(result);
+                }
+            } else if state.bumped() < capacity {
+                if slab.compare_and_set(state, state.bump()) {
+                    let generation = table.generations[index].load(Ordering::SeqCst);
+                    let result = slab_address(shmem_id, object_size, state.bumped())?
+                        .with_generation(generation_bits(generation));
+                    self.init_object(result, generation).ok()?;
+                    return Some(result);
+                }
+            } else {
+                return None;
             }
         }
     }
 
-    #[cfg_attr(feature = "no-panic", no_panic)]
-    pub fn free_bytes(&self, addr: SharedAddressRange) -> Option<()> {
-        let free_list = &self.metadata().free_lists[addr.object_size().0 as usize];
-        let bytes = self.get_bytes(addr)?;
+    // Whether a slab of the given size class has objects which can be allocated.
+    fn slab_has_room(&self, table: &ShmemTable, index: usize, object_size: SizeClass) -> bool {
+        let state = table.slabs[index].load(Ordering::SeqCst);
+        let capacity = object_size.to_usize().map_or(0, slab_capacity);
+        !state.is_released()
+            && table.size_classes[index].load(Ordering::SeqCst) == object_size.0
+            && (state.head().is_some() || state.bumped() < capacity)
+    }
+
+    // Allocates an object at the start of a new slab.
+    fn alloc_slab(&self, object_size: SizeClass) -> Result<SharedAddressRange, SharedDataError> {
+        let object_bytes = object_size
+            .to_usize()
+            .ok_or(SharedDataError::TooLarge(max_object_size()))?;
+        let capacity = slab_capacity(object_bytes);
+        let shmem_id = self.alloc_shmem(capacity * object_bytes)?;
+        let (table, _, index) = self
+            .shmem_entry(shmem_id)
+            .ok_or(SharedDataError::OutOfSegments)?;
+        // Nobody else can use the slab until it has a class and a state.
+        table.size_classes[index].store(object_size.0, Ordering::SeqCst);
+        let slab = &table.slabs[index];
+        slab.store(slab.load(Ordering::SeqCst).reset(), Ordering::SeqCst);
+        let generation = table.generations[index].load(Ordering::SeqCst);
+        let result = SharedAddressRange::new(shmem_id, ObjectOffset::default(), object_size)
+            .with_generation(generation_bits(generation));
+        self.init_object(result, generation)?;
+        if capacity > 1 {
+            table.partial[object_size.0 as usize].fetch_or(1 << index, Ordering::SeqCst);
+        }
+        Ok(result)
+    }
+
+    // Sets up an object which has been bumped off the unused memory of a slab.
+    // Objects start at the slab's generation, rather than zero, so that addresses
+    // of objects in earlier shared memory in the same slot are stale.
+    fn init_object(
+        &self,
+        address: SharedAddressRange,
+        generation: u64,
+    ) -> Result<(), SharedDataError> {
+        let (header, _) = self.get_object_unchecked(address)?;
+        header.generation.store(generation, Ordering::SeqCst);
+        header.type_tag.store(UNTAGGED, Ordering::SeqCst);
+        debug!("Allocated {:?}", address);
+        let object_size = address.object_size();
+        let metadata = self.metadata();
+        metadata
+            .bytes_allocated
+            .fetch_add(object_size.to_u64().unwrap_or(0), Ordering::SeqCst);
+        metadata.live_objects[object_size.0 as usize].fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Large objects are given shared memory of their own, which is freed along with them.
+    // The slot may be reused, so the object gets the slot's generation,
+    // which is bumped when it is freed.
+    fn alloc_whole_shmem(&self, object_size: usize) -> Result<SharedAddressRange, SharedDataError> {
+        let shmem_size = ((object_size - 1) / PAGE_SIZE + 1) * PAGE_SIZE;
+        let shmem_id = self.alloc_shmem(shmem_size)?;
+        let (table, _, index) = self
+            .shmem_entry(shmem_id)
+            .ok_or(SharedDataError::OutOfSegments)?;
+        table.size_classes[index].store(SizeClass::WHOLE_SHMEM.0, Ordering::SeqCst);
+        let generation = table.generations[index].load(Ordering::SeqCst);
+        let object_offset = ObjectOffset::default();
+        let result = SharedAddressRange::new(shmem_id, object_offset, SizeClass::WHOLE_SHMEM)
+            .with_generation(generation_bits(generation));
+        debug!("Allocated {:?}", result);
+        let metadata = self.metadata();
+        metadata.bytes_allocated.fetch_add(
+            table.sizes[index].load(Ordering::SeqCst) as u64,
+            Ordering::SeqCst,
+        );
+        metadata.live_objects[SizeClass::WHOLE_SHMEM.0 as usize].fetch_add(1, Ordering::SeqCst);
+        Ok(result)
+    }
+
+    /// Frees bytes allocated by this allocator.
+    ///
+    /// Returns an error if the address is stale, because the object has already been freed,
+    /// with the same 128-cycle limit as `get_bytes`.
+    //
+    // I'd like to be able to mark this as `no_panic` but unfortunately
+    // the shared memory crate can panic when mapping a shared memory file.
+    pub fn free_bytes(&self, addr: SharedAddressRange) -> Result<(), SharedDataError> {
+        let (header, bytes) = self.get_object(addr)?;
+        // Bump the generation, so any remaining copies of the address are stale.
+        // If someone else got there first, this is a double free.
+        let generation = self.get_generation(addr, header)?;
+        let current = generation.load(Ordering::SeqCst);
+        if generation_bits(current) != addr.generation()
+            || generation
+                .compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+        {
+            return Err(SharedDataError::StaleAddress(addr));
+        }
+        let object_size = addr.object_size();
+        let metadata = self.metadata();
+        metadata.live_objects[object_size.0 as usize].fetch_sub(1, Ordering::SeqCst);
+        if object_size.is_whole_shmem() {
+            // The object has shared memory of its own, so we can free the shared memory.
+            let shmem_size = self.get_shmem_size(addr.shmem_id()).unwrap_or(0);
+            self.free_shmem(addr.shmem_id(), 0)
+                .ok_or(SharedDataError::UnknownSegment(addr))?;
+            metadata
+                .bytes_allocated
+                .fetch_sub(shmem_size as u64, Ordering::SeqCst);
+            return Ok(());
+        }
+        let addr = addr.with_generation(generation_bits(current + 1));
+        let shmem_id = addr.shmem_id();
+        let (table, _, index) = self
+            .shmem_entry(shmem_id)
+            .ok_or(SharedDataError::UnknownSegment(addr))?;
+        let out_of_bounds = SharedDataError::OutOfBounds(addr);
+        let object_bytes = object_size.to_usize().ok_or(out_of_bounds)?;
+        let capacity = slab_capacity(object_bytes);
+        let object_index = slab_index(addr, shmem_id, object_size, capacity)
+            .ok_or(SharedDataError::OutOfBounds(addr))?;
+        let next: &AtomicSharedAddressRange =
+            Volatile::from_volatile_bytes(bytes).ok_or(SharedDataError::TooSmall {
+                size: bytes.len(),
+                required: mem::size_of::<AtomicSharedAddressRange>(),
+            })?;
+        // Count the object before pushing it, so the length never goes negative
+        // if someone unfrees it straight away.
+        let free_list_length = &metadata.free_list_lengths[object_size.0 as usize];
+        free_list_length.fetch_add(1, Ordering::SeqCst);
+        let slab = &table.slabs[index];
         loop {
-            let head: &AtomicSharedAddressRange = Volatile::from_volatile_bytes(bytes)?;
-            let tail = free_list.load(Ordering::SeqCst);
-            head.store(tail, Ordering::SeqCst);
-            if tail == free_list.compare_and_swap(tail, addr, Ordering::SeqCst) {
+            let state = slab.load(Ordering::SeqCst);
+            if state.is_released() {
+                free_list_length.fetch_sub(1, Ordering::SeqCst);
+                return Err(SharedDataError::UnknownSegment(addr));
+            }
+            // Once every object has been freed, the slab is released, so nobody can allocate
+            // from it, and its shared memory can be freed.
+            if state.live() == 1 {
+                if slab.compare_and_set(state, state.release()) {
+                    debug!("Released slab {:?}", shmem_id);
+                    table.partial[object_size.0 as usize]
+                        .fetch_and(!(1 << index), Ordering::SeqCst);
+                    free_list_length.fetch_sub(state.bumped(), Ordering::SeqCst);
+                    metadata
+                        .bytes_allocated
+                        .fetch_sub((state.bumped() * object_bytes) as u64, Ordering::SeqCst);
+                    let next_generation = self.next_slab_generation(shmem_id, object_size, state);
+                    self.free_shmem(shmem_id, next_generation);
+                    return Ok(());
+                }
+                continue;
+            }
+            // Objects in the free list store the next address with its generation,
+            // so corruption can be detected.
+            let next_address = state
+                .head()
+                .and_then(|head| slab_address(shmem_id, object_size, head))
+                .map(|head| self.with_current_generation(head).unwrap_or(head))
+                .unwrap_or_else(SharedAddressRange::null);
+            next.store(next_address, Ordering::SeqCst);
+            if slab.compare_and_set(state, state.push(object_index)) {
                 debug!("Freed {:?}", addr);
-                return Some(());
+                table.partial[object_size.0 as usize].fetch_or(1 << index, Ordering::SeqCst);
+                return Ok(());
             }
         }
     }
 
-    pub(crate) fn set_event(&self, state: EventState) {
-        // TODO: more than one event
-        self.metadata_shmem.as_owner().set_event(0, state);
+    // A generation later than that of any object in a slab.
+    fn next_slab_generation(
+        &self,
+        shmem_id: ShmemId,
+        object_size: SizeClass,
+        state: SlabState,
+    ) -> u64 {
+        (0..state.bumped())
+            .filter_map(|index| slab_address(shmem_id, object_size, index))
+            .filter_map(|address| self.get_object_unchecked(address).ok())
+            .map(|(header, _)| header.generation.load(Ordering::SeqCst) + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn segments(&self) -> Vec<SegmentStats> {
+        let num_shmems = self.tables().count() * SHMEMS_PER_TABLE;
+        (0..num_shmems)
+            .filter_map(|index| {
+                let shmem_id = ShmemId::from_usize(index)?;
+                let name = self.get_shmem_name(shmem_id)?;
+                Some(SegmentStats {
+                    index,
+                    name: String::from(name.as_str()),
+                    size: self.get_shmem_size(shmem_id)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Statistics for the heap, as seen by every process using it.
+    pub fn stats(&self) -> ShmemStats {
+        let metadata = self.metadata();
+        let segments = self.segments();
+        let size_classes = (0..NUM_SIZE_CLASSES)
+            .filter_map(|index| {
+                let live = metadata.live_objects[index].load(Ordering::SeqCst);
+                let free = metadata.free_list_lengths[index].load(Ordering::SeqCst);
+                if live == 0 && free == 0 {
+                    return None;
+                }
+                Some(SizeClassStats {
+                    object_size: SizeClass(index as u8).to_u64().unwrap_or(0),
+                    live,
+                    free,
+                })
+            })
+            .collect();
+        ShmemStats {
+            segments,
+            bytes_allocated: metadata.bytes_allocated.load(Ordering::SeqCst),
+            size_classes,
+        }
+    }
+
+    // The slabs which are in use, with their table entries and ids.
+    fn slabs(&self) -> impl Iterator<Item = (&ShmemTable, usize, ShmemId, SizeClass)> {
+        self.tables().flat_map(move |(table_index, table)| {
+            (0..SHMEMS_PER_TABLE).filter_map(move |index| {
+                let shmem_id = ShmemId::from_usize(table_index * SHMEMS_PER_TABLE + index)?;
+                let object_size = SizeClass(table.size_classes[index].load(Ordering::SeqCst));
+                let state = table.slabs[index].load(Ordering::SeqCst);
+                if object_size.is_whole_shmem()
+                    || state.is_released()
+                    || self.get_shmem_name(shmem_id).is_none()
+                {
+                    return None;
+                }
+                Some((table, index, shmem_id, object_size))
+            })
+        })
+    }
+
+    // Walks the free list of a slab, stopping if it has a cycle or an address can't be read.
+    // Other processes may be changing the slab, so this is only reliable if they aren't.
+    pub(crate) fn inspect_slab(
+        &self,
+        table: &ShmemTable,
+        index: usize,
+        shmem_id: ShmemId,
+        object_size: SizeClass,
+    ) -> SlabInspection {
+        let state = table.slabs[index].load(Ordering::SeqCst);
+        // Only the head of the list is missing its generation.
+        let mut next = state
+            .head()
+            .and_then(|head| slab_address(shmem_id, object_size, head))
+            .map(|head| self.with_current_generation(head).unwrap_or(head));
+        let mut addresses = Vec::new();
+        let mut visited = HashSet::new();
+        let mut end = FreeListEnd::Null;
+        while let Some(address) = next {
+            if !visited.insert(u64::from(address)) {
+                end = FreeListEnd::Cycle;
+                break;
+            }
+            let tail = self
+                .get_bytes(address)
+                .ok()
+                .and_then(Volatile::<AtomicSharedAddressRange>::from_volatile_bytes);
+            let tail = match tail {
+                Some(tail) => tail.load(Ordering::SeqCst),
+                None => {
+                    end = FreeListEnd::Unreadable(address);
+                    break;
+                }
+            };
+            addresses.push(address);
+            next = Some(tail).filter(|tail| *tail != SharedAddressRange::null());
+        }
+        let object_bytes = object_size.to_usize().unwrap_or(0);
+        SlabInspection {
+            index: shmem_id.to_usize().unwrap_or(0),
+            capacity: slab_capacity(object_bytes),
+            bumped: state.bumped(),
+            live: state.live(),
+            free_list: FreeListInspection {
+                object_size: object_bytes as u64,
+                addresses,
+                end,
+            },
+        }
+    }
+
+    /// Dumps the metadata of the heap, including the contents of the slabs' free lists.
+    pub fn inspect(&self) -> ShmemInspection {
+        let slabs = self
+            .slabs()
+            .map(|(table, index, shmem_id, object_size)| {
+                self.inspect_slab(table, index, shmem_id, object_size)
+            })
+            .collect();
+        ShmemInspection {
+            id: self.id().to_u64(),
+            name: self.name(),
+            num_shmems: self.get_num_shmems(),
+            segments: self.segments(),
+            slabs,
+        }
+    }
+
+    /// Checks the slabs' free lists for corruption.
+    ///
+    /// This is only reliable if no other process is using the heap, for example in tests
+    /// or after recovering from a crash.
+    pub fn verify(&self) -> ShmemReport {
+        let mut report = ShmemReport::default();
+        for (table, index, shmem_id, slab_size) in self.slabs() {
+            let slab = self.inspect_slab(table, index, shmem_id, slab_size);
+            let free_list = slab.free_list;
+            let object_size = free_list.object_size;
+            if free_list.addresses.is_empty() && free_list.end == FreeListEnd::Null {
+                continue;
+            }
+            report.free_lists += 1;
+            report.free_objects += free_list.addresses.len();
+            for &address in &free_list.addresses {
+                if address.object_size() != slab_size {
+                    report.problems.push(ShmemProblem::WrongSizeClass {
+                        object_size,
+                        address,
+                    });
+                } else if address.shmem_id() != shmem_id {
+                    report.problems.push(ShmemProblem::WrongSlab {
+                        object_size,
+                        address,
+                    });
+                } else if slab_index(address, shmem_id, slab_size, slab.bumped).is_none() {
+                    report.problems.push(ShmemProblem::OverlapsUnused {
+                        object_size,
+                        address,
+                    });
+                }
+            }
+            match free_list.end {
+                FreeListEnd::Null => (),
+                FreeListEnd::Cycle => {
+                    let address = free_list.addresses.last().copied();
+                    let address = address.unwrap_or_else(SharedAddressRange::null);
+                    report.problems.push(ShmemProblem::FreeListCycle {
+                        object_size,
+                        address,
+                    });
+                }
+                FreeListEnd::Unreadable(address) => {
+                    if self.get_shmem_name(address.shmem_id()).is_none() {
+                        report.problems.push(ShmemProblem::UnknownSegment {
+                            object_size,
+                            address,
+                        });
+                    } else {
+                        report.problems.push(ShmemProblem::Unreadable {
+                            object_size,
+                            address,
+                        });
+                    }
+                }
+            }
+        }
+        report
+    }
+
+    /// Publishes an object allocated by this allocator, so other processes can look it up by name.
+    ///
+    /// Objects stay published until they are unpublished,
+    /// and each name can only be published once at a time.
+    pub fn publish<T: SharedMemCast>(
+        &self,
+        name: &str,
+        rc: SharedRc<T>,
+    ) -> Result<(), SharedDataError> {
+        let address = SharedRc::address(&rc);
+        if SharedRc::allocator(&rc)?.id() != self.id() {
+            return Err(SharedDataError::UnknownSegment(address));
+        }
+        publish_root(
+            &self.metadata().roots,
+            name,
+            type_fingerprint::<T>(),
+            address,
+        )?;
+        // The published root owns a reference to the object.
+        SharedRc::into_raw_address(rc);
+        Ok(())
+    }
+
+    /// Looks up an object published with `publish`, checking that it has the expected type.
+    pub fn lookup<T: SharedMemCast>(&self, name: &str) -> Result<SharedRc<T>, SharedDataError> {
+        lookup_root(
+            &self.metadata().roots,
+            name,
+            type_fingerprint::<T>(),
+            |address| SharedRc::try_from_address_in(address, self),
+        )
+    }
+
+    /// Unpublishes an object published with `publish`, returning the reference the root owned.
+    pub fn unpublish<T: SharedMemCast>(&self, name: &str) -> Result<SharedRc<T>, SharedDataError> {
+        let address = unpublish_root(&self.metadata().roots, name, type_fingerprint::<T>())?;
+        SharedRc::from_raw_address_in(address, self)
+    }
+}
+
+impl Drop for ShmemAllocator {
+    // Like the heap itself, shared memory which this process created is unlinked
+    // when the allocator is closed, so it isn't left behind.
+    fn drop(&mut self) {
+        for (table_index, local) in self.tables.iter().enumerate() {
+            let (local, table) = match (local.get(), self.table(table_index)) {
+                (Some(local), Some(table)) => (local, table),
+                _ => continue,
+            };
+            for index in 0..SHMEMS_PER_TABLE {
+                let incarnation = table.incarnations[index].load(Ordering::SeqCst);
+                if table.used[index].load(Ordering::SeqCst)
+                    && local.created[index].load(Ordering::SeqCst) == incarnation + 1
+                {
+                    SyncSharedMem::unlink(table.names[index].read_volatile());
+                }
+            }
+        }
     }
+}
+
+fn map_table(shmem: SyncSharedMem) -> Result<BoxRef<SyncSharedMem, ShmemTable>, SharedDataError> {
+    OwningRef::new(Box::new(shmem)).try_map(|bytes| {
+        Volatile::<ShmemTable>::from_volatile_bytes(bytes)
+            .map(|table| table.deref())
+            .ok_or(SharedDataError::TooSmall {
+                size: bytes.len(),
+                required: mem::size_of::<ShmemTable>(),
+            })
+    })
+}
 
-    pub(crate) fn wait_event(&self, timeout: Timeout) {
-        // TODO: more than one event
-        self.metadata_shmem.as_owner().wait_event(0, timeout);
+// The address of the object at the given index in a slab, with generation zero.
+#[cfg_attr(feature = "no-panic", no_panic)]
+fn slab_address(
+    shmem_id: ShmemId,
+    object_size: SizeClass,
+    index: usize,
+) -> Option<SharedAddressRange> {
+    let offset = object_size.to_u64()?.checked_mul(index as u64)?;
+    let object_offset = ObjectOffset::from_u64(offset)?;
+    Some(SharedAddressRange::new(
+        shmem_id,
+        object_offset,
+        object_size,
+    ))
+}
+
+// The index of an object in a slab, if the address is one of the first `limit` objects.
+#[cfg_attr(feature = "no-panic", no_panic)]
+fn slab_index(
+    address: SharedAddressRange,
+    shmem_id: ShmemId,
+    object_size: SizeClass,
+    limit: usize,
+) -> Option<usize> {
+    if address.shmem_id() != shmem_id || address.object_size() != object_size {
+        return None;
+    }
+    let object_bytes = object_size.to_u64()?;
+    let offset = address.object_offset().to_u64()?;
+    let index = offset / object_bytes;
+    if index * object_bytes != offset || index >= limit as u64 {
+        return None;
     }
+    Some(index as usize)
+}
+
+// The bits of an object's generation which are stored in its address.
+fn generation_bits(generation: u64) -> u16 {
+    (generation & ((1 << GENERATION_BITS) - 1)) as u16
 }
 
 lazy_static! {
     pub static ref ALLOCATOR_NAME: Mutex<Option<String>> = Mutex::new(None);
-    pub static ref ALLOCATOR: ShmemAllocator = {
+    pub static ref ALLOCATOR: &'static ShmemAllocator = {
         if let Some(name) = ALLOCATOR_NAME.lock().ok().and_then(|mut name| name.take()) {
-            ShmemAllocator::open(&*name).expect(&format!("Failed to open shared memory {}.", name))
+            ShmemAllocator::open(&*name)
+                .unwrap_or_else(|err| panic!("Failed to open shared memory {}: {}", name, err))
         } else {
             ShmemAllocator::create().expect("Failed to create shared memory")
         }
@@ -310,5 +1216,496 @@ pub fn set_bootstrap_name(name: String) {
 /// This can be called in one process and passed to another, at which point they share an allocator,
 /// and can communicate using shared data.
 pub fn get_bootstrap_name() -> String {
-    String::from(ALLOCATOR.name().as_str())
+    ALLOCATOR.name()
+}
+
+/// Publishes an object in the bootstrap allocator, so other processes can look it up by name.
+pub fn publish<T: SharedMemCast>(name: &str, rc: SharedRc<T>) -> Result<(), SharedDataError> {
+    ALLOCATOR.publish(name, rc)
+}
+
+/// Looks up an object published in the bootstrap allocator.
+pub fn lookup<T: SharedMemCast>(name: &str) -> Result<SharedRc<T>, SharedDataError> {
+    ALLOCATOR.lookup(name)
+}
+
+/// Unpublishes an object published in the bootstrap allocator.
+pub fn unpublish<T: SharedMemCast>(name: &str) -> Result<SharedRc<T>, SharedDataError> {
+    ALLOCATOR.unpublish(name)
+}
+
+#[test]
+fn test_free_shmem() {
+    let alloc = TestHeap::new();
+    // Large allocations get shared memory of their own, rounded up to a page
+    let address = alloc.alloc_bytes(4 << 20).unwrap();
+    assert!(alloc.get_bytes(address).is_ok());
+    assert_eq!(alloc.get_num_shmems(), 1);
+    assert_eq!(
+        alloc.get_shmem_size(address.shmem_id()),
+        Some((4 << 20) + PAGE_SIZE)
+    );
+    let name = alloc.get_shmem_name(address.shmem_id()).unwrap();
+    alloc.free_bytes(address).unwrap();
+    assert!(matches!(
+        alloc.get_bytes(address),
+        Err(SharedDataError::UnknownSegment(_))
+    ));
+    assert_eq!(alloc.get_num_shmems(), 0);
+    // The shared memory has been unlinked, so nobody else can map it
+    assert!(SyncSharedMem::open(name, PAGE_SIZE).is_err());
+    // The shared memory slot gets reused, but the old address is stale
+    let reused = alloc.alloc_bytes(4 << 20).unwrap();
+    assert_eq!(reused.shmem_id(), address.shmem_id());
+    assert_eq!(alloc.get_num_shmems(), 1);
+    assert!(alloc.get_bytes(reused).is_ok());
+    assert!(alloc.get_bytes(address).is_err());
+    assert!(alloc.free_bytes(address).is_err());
+    assert_eq!(alloc.get_num_shmems(), 1);
+    alloc.free_bytes(reused).unwrap();
+    assert_eq!(alloc.get_num_shmems(), 0);
+}
+
+#[test]
+fn test_reused_shmem() {
+    let alloc = TestHeap::new();
+    // Fill the start of a large object, then free its shared memory
+    let address = alloc.alloc_bytes(4 << 20).unwrap();
+    for byte in &alloc.get_bytes(address).unwrap()[..256] {
+        byte.write_volatile(0xff);
+    }
+    alloc.free_bytes(address).unwrap();
+    // The next slab reuses the slot, with new shared memory,
+    // and its objects have later generations than the large object
+    let addresses: Vec<_> = (0..8).map(|_| alloc.alloc_bytes(8).unwrap()).collect();
+    for reused in &addresses {
+        assert_eq!(reused.shmem_id(), address.shmem_id());
+        assert_eq!(alloc.get_bytes(*reused).unwrap()[0].read_volatile(), 0);
+    }
+    assert!(alloc.get_bytes(address).is_err());
+    assert_ne!(addresses[0].generation(), address.generation());
+    // Once the slab is released, the slot is reused again,
+    // and addresses of objects in the slab are stale
+    for reused in &addresses {
+        alloc.free_bytes(*reused).unwrap();
+    }
+    let last = alloc.alloc_bytes(8).unwrap();
+    assert_eq!(last.shmem_id(), address.shmem_id());
+    assert!(addresses
+        .iter()
+        .all(|reused| alloc.get_bytes(*reused).is_err()));
+    assert!(alloc.verify().is_ok());
+}
+
+#[test]
+fn test_stats() {
+    let alloc = TestHeap::new();
+    assert_eq!(alloc.stats(), ShmemStats::default());
+    // Each size class gets a slab of its own
+    let first = alloc.alloc_bytes(1 << 20).unwrap();
+    let addresses: Vec<_> = (0..3).map(|_| alloc.alloc_bytes(8).unwrap()).collect();
+    let stats = alloc.stats();
+    assert_eq!(stats.segments.len(), 2);
+    assert_eq!(stats.total_size(), (5 << 20) + SLAB_SIZE);
+    assert_eq!(stats.bytes_allocated, (5 << 18) + 96);
+    // The first allocation is rounded up to a quarter step, not a power of two
+    assert_eq!(
+        stats.size_classes.last(),
+        Some(&SizeClassStats {
+            object_size: 5 << 18,
+            live: 1,
+            free: 0,
+        })
+    );
+    assert_eq!(
+        stats.size_classes[0],
+        SizeClassStats {
+            object_size: 32,
+            live: 3,
+            free: 0,
+        }
+    );
+    alloc.free_bytes(addresses[0]).unwrap();
+    alloc.free_bytes(addresses[1]).unwrap();
+    let stats = alloc.stats();
+    assert_eq!(stats.live_objects(), 2);
+    assert_eq!(stats.size_classes[0].free, 2);
+    // Reusing freed memory doesn't allocate any more bytes
+    let _reused = alloc.alloc_bytes(8).unwrap();
+    let stats = alloc.stats();
+    assert_eq!(stats.bytes_allocated, (5 << 18) + 96);
+    assert_eq!(stats.size_classes[0].live, 2);
+    assert_eq!(stats.size_classes[0].free, 1);
+    // The stats are shared with other users of the heap
+    let opened = ShmemAllocator::from_shmem(SyncSharedMem::from_shmem(
+        SharedMem::open(&alloc.name()).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(opened.stats(), stats);
+    // Freeing the only object in a slab releases it
+    alloc.free_bytes(first).unwrap();
+    let stats = alloc.stats();
+    assert_eq!(stats.total_size(), SLAB_SIZE);
+    assert_eq!(stats.bytes_allocated, 96);
+    assert_eq!(stats.size_classes.len(), 1);
+}
+
+#[test]
+fn test_whole_shmem_stats() {
+    let alloc = TestHeap::new();
+    let address = alloc.alloc_bytes(4 << 20).unwrap();
+    let shmem_size = (4 << 20) + PAGE_SIZE as u64;
+    assert_eq!(alloc.stats().bytes_allocated, shmem_size);
+    assert_eq!(alloc.stats().live_objects(), 1);
+    alloc.free_bytes(address).unwrap();
+    assert_eq!(alloc.stats().live_objects(), 0);
+    assert_eq!(alloc.stats().bytes_allocated, 0);
+    // The slot gets reused, and only shared memory which is in use is counted
+    let reused = alloc.alloc_bytes(4 << 20).unwrap();
+    assert_eq!(reused.shmem_id(), address.shmem_id());
+    assert_eq!(alloc.stats().bytes_allocated, shmem_size);
+    assert_eq!(alloc.stats().live_objects(), 1);
+    alloc.free_bytes(reused).unwrap();
+}
+
+#[test]
+fn test_inspect() {
+    let alloc = TestHeap::new();
+    let inspection = alloc.inspect();
+    assert_eq!(inspection.name, alloc.name());
+    assert!(inspection.slabs.is_empty());
+    let addresses: Vec<_> = (0..3).map(|_| alloc.alloc_bytes(8).unwrap()).collect();
+    let _live = alloc.alloc_bytes(8).unwrap();
+    for address in &addresses {
+        alloc.free_bytes(*address).unwrap();
+    }
+    let inspection = alloc.inspect();
+    assert_eq!(inspection.segments.len(), 1);
+    assert_eq!(inspection.slabs.len(), 1);
+    let slab = &inspection.slabs[0];
+    assert_eq!(
+        (slab.capacity, slab.bumped, slab.live),
+        (SLAB_SIZE / 32, 4, 1)
+    );
+    let free_list = &slab.free_list;
+    assert_eq!(free_list.object_size, 32);
+    assert_eq!(free_list.end, FreeListEnd::Null);
+    // The free list is in reverse order, and the addresses have new generations
+    let offsets: Vec<_> = free_list
+        .addresses
+        .iter()
+        .map(|address| address.object_offset())
+        .collect();
+    let expected: Vec<_> = addresses
+        .iter()
+        .rev()
+        .map(|address| address.object_offset())
+        .collect();
+    assert_eq!(offsets, expected);
+    assert!(inspection
+        .to_string()
+        .contains("slab in segment 0 of size 32: 1 live, 4 of 8192 bumped, 3 free"));
+    // The heap can be inspected by name, without registering another allocator
+    let by_name = ShmemAllocator::inspect_by_name(&alloc.name()).unwrap();
+    assert_eq!(by_name, inspection);
+    assert_eq!(ALLOCATORS.count(&alloc.name()), 1);
+}
+
+#[test]
+fn test_verify() {
+    let alloc = TestHeap::new();
+    assert!(alloc.verify().is_ok());
+    let addresses: Vec<_> = (0..3).map(|_| alloc.alloc_bytes(8).unwrap()).collect();
+    let _live = alloc.alloc_bytes(8).unwrap();
+    let other = alloc.alloc_bytes(100).unwrap();
+    for address in &addresses {
+        alloc.free_bytes(*address).unwrap();
+    }
+    let report = alloc.verify();
+    assert!(report.is_ok(), "{}", report);
+    assert_eq!(report.free_lists, 1);
+    assert_eq!(report.free_objects, 3);
+    // Corrupt the free list by pointing the last object at various things
+    let shmem_id = addresses[0].shmem_id();
+    let inspection = alloc.inspect();
+    let slab = inspection
+        .slabs
+        .iter()
+        .find(|slab| Some(slab.index) == shmem_id.to_usize())
+        .unwrap();
+    let first = slab.free_list.addresses[0];
+    let last = slab.free_list.addresses[2];
+    let tail: &AtomicSharedAddressRange =
+        Volatile::from_volatile_bytes(alloc.get_bytes(last).unwrap()).unwrap();
+    tail.store(first, Ordering::SeqCst);
+    assert_eq!(
+        alloc.verify().problems,
+        vec![ShmemProblem::FreeListCycle {
+            object_size: 32,
+            address: last,
+        }]
+    );
+    tail.store(other, Ordering::SeqCst);
+    assert_eq!(
+        alloc.verify().problems,
+        vec![ShmemProblem::WrongSizeClass {
+            object_size: 32,
+            address: other,
+        }]
+    );
+    let other_slab = SharedAddressRange::new(
+        other.shmem_id(),
+        ObjectOffset::from_u64(1024).unwrap(),
+        SizeClass(0),
+    );
+    tail.store(other_slab, Ordering::SeqCst);
+    assert_eq!(
+        alloc.verify().problems,
+        vec![ShmemProblem::WrongSlab {
+            object_size: 32,
+            address: other_slab,
+        }]
+    );
+    tail.store(addresses[1], Ordering::SeqCst);
+    assert_eq!(
+        alloc.verify().problems,
+        vec![ShmemProblem::Unreadable {
+            object_size: 32,
+            address: addresses[1],
+        }]
+    );
+    let out_of_bounds = SharedAddressRange::new(
+        shmem_id,
+        ObjectOffset::from_u64(1 << 30).unwrap(),
+        SizeClass(0),
+    );
+    tail.store(out_of_bounds, Ordering::SeqCst);
+    assert_eq!(
+        alloc.verify().problems,
+        vec![ShmemProblem::Unreadable {
+            object_size: 32,
+            address: out_of_bounds,
+        }]
+    );
+    let unknown = SharedAddressRange::new(
+        ShmemId::from_usize(MAX_SHMEMS - 1).unwrap(),
+        ObjectOffset::default(),
+        SizeClass(0),
+    );
+    tail.store(unknown, Ordering::SeqCst);
+    assert_eq!(
+        alloc.verify().problems,
+        vec![ShmemProblem::UnknownSegment {
+            object_size: 32,
+            address: unknown,
+        }]
+    );
+    let unallocated = SharedAddressRange::new(
+        shmem_id,
+        ObjectOffset::from_u64((SLAB_SIZE - 32) as u64).unwrap(),
+        SizeClass(0),
+    );
+    tail.store(unallocated, Ordering::SeqCst);
+    assert!(alloc
+        .verify()
+        .problems
+        .contains(&ShmemProblem::OverlapsUnused {
+            object_size: 32,
+            address: unallocated,
+        }));
+    tail.store(SharedAddressRange::null(), Ordering::SeqCst);
+    assert!(alloc.verify().is_ok());
+}
+
+#[test]
+fn test_many_shmems() {
+    let alloc = TestHeap::new();
+    let ids: Vec<_> = (0..100).map(|_| alloc.alloc_shmem(4096).unwrap()).collect();
+    assert_eq!(ids[99], ShmemId::from_usize(99).unwrap());
+    assert_eq!(alloc.get_num_shmems(), 100);
+    // Another view of the heap finds the segments through the linked tables
+    let opened = ShmemAllocator::from_shmem(SyncSharedMem::from_shmem(
+        SharedMem::open(&alloc.name()).unwrap(),
+    ))
+    .unwrap();
+    let address = SharedAddressRange::new(ids[99], ObjectOffset::default(), SizeClass::WHOLE_SHMEM);
+    assert_eq!(opened.get_shmem(address).unwrap().len(), 4096);
+    assert_eq!(opened.stats().segments.len(), 100);
+    // Freed slots in overflow tables get reused
+    alloc.free_shmem(ids[70], 0).unwrap();
+    assert_eq!(alloc.get_num_shmems(), 99);
+    assert_eq!(alloc.alloc_shmem(4096).unwrap(), ids[70]);
+}
+
+#[test]
+fn test_stale_address() {
+    let alloc = TestHeap::new();
+    // Keep the slab alive, so the object goes on its free list
+    let _live = alloc.alloc_bytes(8).unwrap();
+    let address = alloc.alloc_bytes(8).unwrap();
+    assert!(alloc.get_bytes(address).is_ok());
+    alloc.free_bytes(address).unwrap();
+    assert!(matches!(
+        alloc.get_bytes(address),
+        Err(SharedDataError::StaleAddress(_))
+    ));
+    assert!(matches!(
+        alloc.free_bytes(address),
+        Err(SharedDataError::StaleAddress(_))
+    ));
+    // The memory gets reused, with a new generation
+    let reused = alloc.alloc_bytes(8).unwrap();
+    assert_eq!(reused.object_offset(), address.object_offset());
+    assert_ne!(reused, address);
+    assert!(alloc.get_bytes(reused).is_ok());
+    assert!(alloc.get_bytes(address).is_err());
+}
+
+#[test]
+fn test_slab_release() {
+    let alloc = TestHeap::new();
+    // Fill a slab, and start another
+    let size = (64 << 10) - OBJECT_HEADER_SIZE;
+    let addresses: Vec<_> = (0..5).map(|_| alloc.alloc_bytes(size).unwrap()).collect();
+    assert_eq!(alloc.stats().segments.len(), 2);
+    // Once every object in the full slab is freed, its shared memory is released
+    for address in &addresses[..4] {
+        alloc.free_bytes(*address).unwrap();
+    }
+    let stats = alloc.stats();
+    assert_eq!(stats.segments.len(), 1);
+    assert_eq!(stats.bytes_allocated, 64 << 10);
+    assert_eq!(
+        stats.size_classes.last(),
+        Some(&SizeClassStats {
+            object_size: 64 << 10,
+            live: 1,
+            free: 0,
+        })
+    );
+    assert!(alloc.get_bytes(addresses[0]).is_err());
+    // and can be reused by objects of another size
+    let reused = alloc.alloc_bytes(8).unwrap();
+    assert_eq!(reused.shmem_id(), addresses[0].shmem_id());
+    assert!(alloc.verify().is_ok());
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn test_large_object() {
+    // Objects can be bigger than 4GiB. The shared memory is sparse,
+    // so this only uses the pages we touch.
+    let alloc = TestHeap::new();
+    let size = (1 << 32) + 1;
+    let address = alloc.alloc_bytes(size).unwrap();
+    let bytes = alloc.get_bytes(address).unwrap();
+    assert!(bytes.len() >= size);
+    bytes[size - 1].write_volatile(37);
+    assert_eq!(bytes[size - 1].read_volatile(), 37);
+    alloc.free_bytes(address).unwrap();
+    assert!(alloc.get_bytes(address).is_err());
+}
+
+#[test]
+fn test_publish_in() {
+    let alloc = TestHeap::new();
+    let rc = SharedRc::new_in(AtomicUsize::new(37), &alloc);
+    alloc.publish("answer", rc.clone()).unwrap();
+    assert_eq!(SharedRc::strong_count(&rc), 2);
+    // Roots are per heap, so the bootstrap heap doesn't see it
+    assert!(ALLOCATOR.lookup::<AtomicUsize>("answer").is_err());
+    let found: SharedRc<AtomicUsize> = alloc.lookup("answer").unwrap();
+    assert!(SharedRc::ptr_eq(&rc, &found));
+    drop(found);
+    // Unpublishing hands back the root's reference, from the same heap
+    let unpublished: SharedRc<AtomicUsize> = alloc.unpublish("answer").unwrap();
+    assert!(SharedRc::ptr_eq(&rc, &unpublished));
+    assert_eq!(SharedRc::strong_count(&rc), 2);
+    drop(unpublished);
+    assert_eq!(SharedRc::strong_count(&rc), 1);
+    assert!(alloc.lookup::<AtomicUsize>("answer").is_err());
+}
+
+#[cfg(test)]
+use crate::unsafe_code::TestHeap;
+#[cfg(test)]
+use std::thread;
+
+#[test]
+fn test_errors() {
+    assert!(matches!(
+        ShmemAllocator::open("/shared-data-does-not-exist"),
+        Err(SharedDataError::Shmem(_))
+    ));
+    assert!(matches!(
+        ALLOCATOR.alloc_bytes(usize::MAX),
+        Err(SharedDataError::TooLarge(_))
+    ));
+}
+
+#[test]
+fn test_free_list_stress() {
+    let heap = TestHeap::new();
+    let alloc = &*heap;
+    thread::scope(|scope| {
+        for thread_id in 0..8 {
+            scope.spawn(move || {
+                for iteration in 0..20_000 {
+                    let addresses: Vec<_> = (0..(1 + iteration % 4))
+                        .map(|_| alloc.alloc_bytes(8).unwrap())
+                        .collect();
+                    let atomics: Vec<&Volatile<AtomicUsize>> = addresses
+                        .iter()
+                        .map(|&address| {
+                            Volatile::from_volatile_bytes(alloc.get_bytes(address).unwrap())
+                                .unwrap()
+                        })
+                        .collect();
+                    for (index, atomic) in atomics.iter().enumerate() {
+                        atomic.store(thread_id * 4 + index, Ordering::SeqCst);
+                    }
+                    thread::yield_now();
+                    // If the free list handed out the same memory twice, this will fail
+                    for (index, atomic) in atomics.iter().enumerate() {
+                        assert_eq!(atomic.load(Ordering::SeqCst), thread_id * 4 + index);
+                    }
+                    for address in addresses {
+                        alloc.free_bytes(address).unwrap();
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn test_slab_release_stress() {
+    // Each slab only holds a few of these, so slabs are released and reused
+    // while other threads are allocating from them
+    let heap = TestHeap::new();
+    let alloc = &*heap;
+    let size = (64 << 10) - OBJECT_HEADER_SIZE;
+    thread::scope(|scope| {
+        for thread_id in 0..4 {
+            scope.spawn(move || {
+                for _ in 0..200 {
+                    let addresses: Vec<_> =
+                        (0..3).map(|_| alloc.alloc_bytes(size).unwrap()).collect();
+                    for address in &addresses {
+                        alloc.get_bytes(*address).unwrap()[0].write_volatile(thread_id);
+                    }
+                    thread::yield_now();
+                    for address in addresses {
+                        assert_eq!(
+                            alloc.get_bytes(address).unwrap()[0].read_volatile(),
+                            thread_id
+                        );
+                        alloc.free_bytes(address).unwrap();
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(alloc.stats().live_objects(), 0);
+    assert!(alloc.verify().is_ok());
 }
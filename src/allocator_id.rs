@@ -0,0 +1,20 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+/// An identifier for an allocator, which is the same in every process using it.
+///
+/// Containers live in shared memory, so they can't point at their allocator,
+/// instead they remember its id.
+#[derive(Clone, Copy, Default, Eq, Debug, PartialEq)]
+pub struct AllocatorId(u64);
+
+impl AllocatorId {
+    pub fn random() -> AllocatorId {
+        AllocatorId(rand::random())
+    }
+
+    pub fn to_u64(self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,46 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Prints the metadata of a heap of shared memory, for debugging.
+//!
+//! Usage:
+//!   shared-data-inspect <shmem name>
+//!   shared-data-inspect --address <u64>
+
+use shared_data::SharedAddressRange;
+use shared_data::ShmemAllocator;
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("Usage: shared-data-inspect <shmem name>");
+    eprintln!("       shared-data-inspect --address <u64>");
+    process::exit(2);
+}
+
+fn parse_u64(arg: &str) -> Option<u64> {
+    if let Some(hex) = arg.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        arg.parse().ok()
+    }
+}
+
+fn main() {
+    let _ = env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [flag, address] if flag == "--address" => {
+            let address = parse_u64(address).unwrap_or_else(|| usage());
+            println!("{}", SharedAddressRange::from(address));
+        }
+        [name] if !name.starts_with('-') => match ShmemAllocator::inspect_by_name(name) {
+            Ok(inspection) => print!("{}", inspection),
+            Err(err) => {
+                eprintln!("Failed to open {}: {}", name, err);
+                process::exit(1);
+            }
+        },
+        _ => usage(),
+    }
+}
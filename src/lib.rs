@@ -4,48 +4,92 @@
 
 #![deny(unsafe_code)]
 
+// So that derived impls work inside this crate.
+extern crate self as shared_data;
+
 mod allocator;
-mod atomic_shared_address;
+mod allocator_id;
 mod atomic_shared_address_range;
 mod object_offset;
-mod object_size;
-mod shared_address;
+mod reading;
 mod shared_address_range;
 mod shared_box;
 mod shared_channel;
+mod shared_data_error;
+mod shared_futex;
+mod shared_hash_map;
+mod shared_header;
+mod shared_mutex;
 mod shared_option;
 mod shared_rc;
+mod shared_root;
+mod shared_string;
 mod shared_vec;
 mod shmem_id;
+mod shmem_inspection;
 mod shmem_name;
+mod shmem_report;
+mod shmem_stats;
+mod shmem_table;
+mod size_class;
+mod slab_state;
 
 // All unsafe code lives here
 mod unsafe_code;
 
-// Reexport traits.
-pub use shared_memory::SharedMemCast;
+// Derive macros for traits.
+pub use shared_data_derive::SharedMemCast;
+pub use shared_data_derive::SharedMemRef;
 
 pub use allocator::get_bootstrap_name;
+pub use allocator::lookup;
+pub use allocator::publish;
 pub use allocator::set_bootstrap_name;
+pub use allocator::unpublish;
+pub use allocator::ShmemAllocator;
 pub use shared_address_range::SharedAddressRange;
 pub use shared_box::SharedBox;
 pub use shared_channel::channel;
+pub use shared_channel::channel_in;
+pub use shared_channel::sync_channel;
+pub use shared_channel::sync_channel_in;
+pub use shared_channel::RecvError;
+pub use shared_channel::RecvTimeoutError;
+pub use shared_channel::SendError;
 pub use shared_channel::SharedReceiver;
 pub use shared_channel::SharedSender;
+pub use shared_channel::TryRecvError;
+pub use shared_channel::TrySendError;
+pub use shared_data_error::SharedDataError;
+pub use shared_hash_map::SharedHashMap;
+pub use shared_mutex::SharedMutex;
+pub use shared_mutex::SharedMutexGuard;
 pub use shared_option::SharedOption;
 pub use shared_rc::SharedRc;
+pub use shared_rc::SharedWeak;
+pub use shared_string::SharedString;
 pub use shared_vec::SharedVec;
+pub use shmem_inspection::FreeListEnd;
+pub use shmem_inspection::FreeListInspection;
+pub use shmem_inspection::ShmemInspection;
+pub use shmem_inspection::SlabInspection;
+pub use shmem_report::ShmemProblem;
+pub use shmem_report::ShmemReport;
+pub use shmem_stats::SegmentStats;
+pub use shmem_stats::ShmemStats;
+pub use shmem_stats::SizeClassStats;
+pub use unsafe_code::SharedMemCast;
 pub use unsafe_code::SharedMemRef;
 pub use unsafe_code::Volatile;
 
 // Should these be publicly exported
-pub(crate) use allocator::ShmemAllocator;
 pub(crate) use allocator::ALLOCATOR;
-pub(crate) use atomic_shared_address::AtomicSharedAddress;
+pub(crate) use allocator_id::AllocatorId;
 pub(crate) use atomic_shared_address_range::AtomicSharedAddressRange;
 pub(crate) use object_offset::ObjectOffset;
-pub(crate) use object_size::ObjectSize;
-pub(crate) use shared_address::SharedAddress;
+pub(crate) use reading::Reading;
 pub(crate) use shmem_id::ShmemId;
 pub(crate) use shmem_name::ShmemName;
+pub(crate) use size_class::SizeClass;
+pub(crate) use slab_state::AtomicSlabState;
 pub(crate) use unsafe_code::SyncSharedMem;
@@ -8,4 +8,24 @@ use num_derive::ToPrimitive;
 #[derive(
     Clone, Copy, Default, Eq, Debug, Ord, PartialEq, PartialOrd, FromPrimitive, ToPrimitive,
 )]
-pub struct ObjectOffset(u32);
+pub struct ObjectOffset(u64);
+
+/// Objects are at least this big (including their header), and are allocated at
+/// multiples of this, so offsets are packed in units of this size.
+pub(crate) const OFFSET_GRANULE: u64 = 32;
+
+impl ObjectOffset {
+    /// The offset in units of `OFFSET_GRANULE`, if it is a multiple of it.
+    pub(crate) fn to_granules(self) -> Option<u64> {
+        let granules = self.0 / OFFSET_GRANULE;
+        if granules * OFFSET_GRANULE == self.0 {
+            Some(granules)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn from_granules(granules: u64) -> Option<ObjectOffset> {
+        granules.checked_mul(OFFSET_GRANULE).map(ObjectOffset)
+    }
+}
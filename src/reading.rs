@@ -0,0 +1,24 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// Counts as a reader of shared memory while it is alive, even if the reader panics.
+///
+/// Writers wait for the count to reach zero before they reuse what is being read.
+pub(crate) struct Reading<'a>(&'a AtomicUsize);
+
+impl<'a> Reading<'a> {
+    pub(crate) fn new(readers: &'a AtomicUsize) -> Reading<'a> {
+        readers.fetch_add(1, Ordering::SeqCst);
+        Reading(readers)
+    }
+}
+
+impl<'a> Drop for Reading<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
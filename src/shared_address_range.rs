@@ -2,38 +2,63 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use crate::object_offset::OFFSET_GRANULE;
 use crate::ObjectOffset;
-use crate::ObjectSize;
 use crate::ShmemId;
+use crate::SizeClass;
 use num_traits::FromPrimitive;
 use num_traits::ToPrimitive;
+use std::fmt;
 
 #[cfg(feature = "no-panic")]
 use no_panic::no_panic;
 
+// An address range is packed into 64 bits as 35 bits of object offset (in 32 byte units),
+// 6 bits of object size (which is stored as a size class), 7 bits of generation
+// and 16 bits of shmem id.
+const OFFSET_BITS: u32 = 35;
+const OBJECT_SIZE_SHIFT: u32 = OFFSET_BITS;
+const OBJECT_SIZE_BITS: u32 = 6;
+const GENERATION_SHIFT: u32 = OBJECT_SIZE_SHIFT + OBJECT_SIZE_BITS;
+const SHMEM_ID_SHIFT: u32 = GENERATION_SHIFT + GENERATION_BITS;
+const SHMEM_ID_BITS: u32 = 16;
+
+/// The number of bits of an object's generation that are stored in its address.
+pub(crate) const GENERATION_BITS: u32 = 7;
+
+/// Offsets are less than this, so this is the largest shared memory an address can be in.
+pub(crate) const MAX_SHMEM_SIZE: u64 = OFFSET_GRANULE << OFFSET_BITS;
+
 /// A range of addresses in shared memory, packed into 64 bits.
-#[derive(Clone, Copy, Eq, Debug, PartialEq)]
-pub struct SharedAddressRange {
-    shmem_id: ShmemId,
-    shmem_size: ObjectSize,
-    object_offset: ObjectOffset,
-    object_size: ObjectSize,
+///
+/// The address includes the low bits of the generation of the object, so that an address
+/// which is kept after the object has been freed is detected, even if the memory has been
+/// reused, as long as it hasn't been reused a multiple of 128 times.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct SharedAddressRange(u64);
+
+#[cfg_attr(feature = "no-panic", no_panic)]
+fn pack(value: u64, shift: u32, bits: u32) -> u64 {
+    (value & ((1 << bits) - 1)) << shift
 }
 
 impl SharedAddressRange {
+    /// A new address range with generation zero. The offset should be a multiple of 32.
     #[cfg_attr(feature = "no-panic", no_panic)]
     pub(crate) fn new(
         shmem_id: ShmemId,
-        shmem_size: ObjectSize,
         object_offset: ObjectOffset,
-        object_size: ObjectSize,
+        object_size: SizeClass,
     ) -> SharedAddressRange {
-        SharedAddressRange {
-            shmem_id,
-            shmem_size,
-            object_offset,
-            object_size,
-        }
+        let offset = object_offset.to_granules().unwrap_or(0);
+        SharedAddressRange(
+            pack(
+                shmem_id.to_u64().unwrap_or(0),
+                SHMEM_ID_SHIFT,
+                SHMEM_ID_BITS,
+            ) | pack(offset, 0, OFFSET_BITS)
+                | pack(object_size.0 as u64, OBJECT_SIZE_SHIFT, OBJECT_SIZE_BITS),
+        )
     }
 
     #[cfg_attr(feature = "no-panic", no_panic)]
@@ -41,27 +66,107 @@ impl SharedAddressRange {
         SharedAddressRange::from(0)
     }
 
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    fn unpack(self, shift: u32, bits: u32) -> u64 {
+        (self.0 >> shift) & ((1 << bits) - 1)
+    }
+
     #[cfg_attr(feature = "no-panic", no_panic)]
     pub(crate) fn shmem_id(self) -> ShmemId {
-        self.shmem_id
+        ShmemId::from_u64(self.unpack(SHMEM_ID_SHIFT, SHMEM_ID_BITS)).unwrap_or_default()
     }
 
+    /// The generation of the object, which changes each time it is freed.
     #[cfg_attr(feature = "no-panic", no_panic)]
-    pub(crate) fn object_size(&self) -> ObjectSize {
-        self.object_size
+    pub(crate) fn generation(self) -> u16 {
+        self.unpack(GENERATION_SHIFT, GENERATION_BITS) as u16
+    }
+
+    /// The same address with another generation, which wraps around.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub(crate) fn with_generation(self, generation: u16) -> SharedAddressRange {
+        let mask = pack(u64::MAX, GENERATION_SHIFT, GENERATION_BITS);
+        let generation = pack(generation as u64, GENERATION_SHIFT, GENERATION_BITS);
+        SharedAddressRange((self.0 & !mask) | generation)
+    }
+
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub(crate) fn object_size(&self) -> SizeClass {
+        SizeClass(self.unpack(OBJECT_SIZE_SHIFT, OBJECT_SIZE_BITS) as u8)
     }
 
     #[cfg_attr(feature = "no-panic", no_panic)]
     pub(crate) fn object_offset(&self) -> ObjectOffset {
-        self.object_offset
+        ObjectOffset::from_granules(self.unpack(0, OFFSET_BITS)).unwrap_or_default()
     }
 
+    /// The end of the object, or `None` if it fills its segment, which has to be looked up.
     #[cfg_attr(feature = "no-panic", no_panic)]
     pub(crate) fn object_end(&self) -> Option<ObjectOffset> {
         ObjectOffset::from_u64(
-            self.object_offset
+            self.object_offset()
                 .to_u64()?
-                .checked_add(self.object_size.to_u64()?)?,
+                .checked_add(self.object_size().to_u64()?)?,
         )
     }
 }
+
+impl From<u64> for SharedAddressRange {
+    fn from(data: u64) -> SharedAddressRange {
+        SharedAddressRange(data)
+    }
+}
+
+impl From<SharedAddressRange> for u64 {
+    fn from(address: SharedAddressRange) -> u64 {
+        address.0
+    }
+}
+
+impl fmt::Debug for SharedAddressRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedAddressRange")
+            .field("shmem_id", &self.shmem_id())
+            .field("generation", &self.generation())
+            .field("object_offset", &self.object_offset())
+            .field("object_size", &self.object_size())
+            .finish()
+    }
+}
+
+impl fmt::Display for SharedAddressRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "segment {} offset {:#x} size {} generation {}",
+            self.shmem_id().to_u64().unwrap_or(0),
+            self.object_offset().to_u64().unwrap_or(0),
+            self.object_size().to_u64().unwrap_or(0),
+            self.generation(),
+        )
+    }
+}
+
+#[test]
+fn test_large_address() {
+    let shmem_id = ShmemId::from_u64(60000).unwrap();
+    let object_offset = ObjectOffset::from_u64((1 << 38) + 1024).unwrap();
+    let object_size = SizeClass::ceil(5 << 20);
+    let address = SharedAddressRange::new(shmem_id, object_offset, object_size);
+    let address = SharedAddressRange::from(u64::from(address)).with_generation(133);
+    assert_eq!(address.shmem_id(), shmem_id);
+    assert_eq!(address.generation(), 5);
+    assert_eq!(address.object_offset(), object_offset);
+    assert_eq!(address.object_size(), object_size);
+    assert_eq!(address.object_end(), None);
+    let object_size = SizeClass::ceil(1 << 20);
+    let address = SharedAddressRange::new(shmem_id, object_offset, object_size);
+    assert_eq!(address.object_size(), object_size);
+    assert_eq!(
+        address.object_end().and_then(|end| end.to_u64()),
+        Some((1 << 38) + (1 << 20) + 1024)
+    );
+    let address = address.with_generation(5);
+    assert_ne!(address.with_generation(6), address);
+    assert_eq!(address.with_generation(6).with_generation(5), address);
+}
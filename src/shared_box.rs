@@ -2,13 +2,15 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use crate::shared_header::type_fingerprint;
+use crate::AllocatorId;
 use crate::SharedAddressRange;
+use crate::SharedDataError;
+use crate::SharedMemCast;
 use crate::SharedMemRef;
 use crate::ShmemAllocator;
 use crate::Volatile;
 use crate::ALLOCATOR;
-use num_traits::ToPrimitive;
-use shared_memory::SharedMemCast;
 use std::convert::From;
 use std::convert::TryFrom;
 use std::marker::PhantomData;
@@ -18,28 +20,39 @@ use std::ops::Deref;
 /// An owned pointer into shared memory.
 pub struct SharedBox<T: SharedMemCast> {
     address: SharedAddressRange,
+    allocator_id: AllocatorId,
     marker: PhantomData<T>,
 }
 
 impl<T: SharedMemCast> SharedBox<T> {
-    pub(crate) fn new_in(data: T, alloc: &ShmemAllocator) -> Option<SharedBox<T>> {
+    /// Allocates a new box using the given allocator, returning an error if allocation failed.
+    pub fn try_new_in(data: T, alloc: &ShmemAllocator) -> Result<SharedBox<T>, SharedDataError> {
         let size = mem::size_of::<T>();
-        let address = alloc.alloc_bytes(size)?;
+        let address = alloc.alloc_tagged(size, type_fingerprint::<T>())?;
         let bytes = alloc.get_bytes(address)?;
-        let volatile = Volatile::<T>::from_volatile_bytes(bytes)?;
+        let volatile =
+            Volatile::<T>::from_volatile_bytes(bytes).ok_or(SharedDataError::TooSmall {
+                size: bytes.len(),
+                required: size,
+            })?;
+        let allocator_id = alloc.id();
         let marker = PhantomData;
         volatile.write_volatile(data);
-        Some(SharedBox { address, marker })
+        Ok(SharedBox {
+            address,
+            allocator_id,
+            marker,
+        })
     }
 
-    pub(crate) fn get_in<'a>(&'a self, alloc: &'a ShmemAllocator) -> Option<&'a Volatile<T>> {
-        let bytes = alloc.get_bytes(self.address)?;
-        Volatile::from_volatile_bytes(bytes)
+    /// Allocates a new box using the given allocator, panicing if allocation failed.
+    pub fn new_in(data: T, alloc: &ShmemAllocator) -> SharedBox<T> {
+        SharedBox::try_new_in(data, alloc).expect("Failed to allocate shared box")
     }
 
-    /// Allocates a new box in shared memory, returning `None` if allocation failed.
-    pub fn try_new(data: T) -> Option<SharedBox<T>> {
-        SharedBox::new_in(data, &ALLOCATOR)
+    /// Allocates a new box in shared memory, returning an error if allocation failed.
+    pub fn try_new(data: T) -> Result<SharedBox<T>, SharedDataError> {
+        SharedBox::try_new_in(data, *ALLOCATOR)
     }
 
     /// Allocates a new box in shared memory, panicing if allocation failed.
@@ -47,9 +60,13 @@ impl<T: SharedMemCast> SharedBox<T> {
         SharedBox::try_new(data).expect("Failed to allocate shared box")
     }
 
-    /// Accesses a box in shared memory, returning `None` if the box refers to inaccessible memory.
-    pub fn try_get(&self) -> Option<&Volatile<T>> {
-        self.get_in(&ALLOCATOR)
+    /// Accesses a box in shared memory, returning an error if the box refers to inaccessible memory.
+    pub fn try_get(&self) -> Result<&Volatile<T>, SharedDataError> {
+        let bytes = self.allocator()?.get_bytes(self.address)?;
+        Volatile::from_volatile_bytes(bytes).ok_or(SharedDataError::TooSmall {
+            size: bytes.len(),
+            required: mem::size_of::<T>(),
+        })
     }
 
     /// Accesses a box in shared memory, panicing if the box refers to inaccessible memory.
@@ -62,26 +79,53 @@ impl<T: SharedMemCast> SharedBox<T> {
         self.address
     }
 
+    /// The allocator the box was allocated in, if this process is using it.
+    pub(crate) fn allocator(&self) -> Result<&'static ShmemAllocator, SharedDataError> {
+        ShmemAllocator::from_id(self.allocator_id)
+    }
+
+    pub(crate) fn allocator_id(&self) -> AllocatorId {
+        self.allocator_id
+    }
+
     /// Create a box from a shared address.
-    pub(crate) fn unchecked_from_address(address: SharedAddressRange) -> SharedBox<T> {
+    pub(crate) fn unchecked_from_address(
+        address: SharedAddressRange,
+        allocator_id: AllocatorId,
+    ) -> SharedBox<T> {
         SharedBox {
             address,
+            allocator_id,
             marker: PhantomData,
         }
     }
-}
 
-impl<T: SharedMemCast> TryFrom<SharedAddressRange> for SharedBox<T> {
-    type Error = ();
-    fn try_from(address: SharedAddressRange) -> Result<SharedBox<T>, ()> {
-        if mem::size_of::<T>() <= address.object_size().to_usize().ok_or(())? {
-            Ok(SharedBox::unchecked_from_address(address))
+    /// Create a box from a shared address in the given allocator,
+    /// checking that the address is a box of the right type.
+    pub(crate) fn try_from_address_in(
+        address: SharedAddressRange,
+        alloc: &ShmemAllocator,
+    ) -> Result<SharedBox<T>, SharedDataError> {
+        let bytes = alloc.get_tagged_bytes(address, type_fingerprint::<T>())?;
+        let required = mem::size_of::<T>();
+        if required <= bytes.len() {
+            Ok(SharedBox::unchecked_from_address(address, alloc.id()))
         } else {
-            Err(())
+            Err(SharedDataError::TooSmall {
+                size: bytes.len(),
+                required,
+            })
         }
     }
 }
 
+impl<T: SharedMemCast> TryFrom<SharedAddressRange> for SharedBox<T> {
+    type Error = SharedDataError;
+    fn try_from(address: SharedAddressRange) -> Result<SharedBox<T>, SharedDataError> {
+        SharedBox::try_from_address_in(address, *ALLOCATOR)
+    }
+}
+
 impl<T: SharedMemCast> From<SharedBox<T>> for SharedAddressRange {
     fn from(boxed: SharedBox<T>) -> SharedAddressRange {
         let address = boxed.address;
@@ -100,13 +144,29 @@ impl<T: SharedMemCast + SharedMemRef> Deref for SharedBox<T> {
 impl<T: SharedMemCast> Drop for SharedBox<T> {
     fn drop(&mut self) {
         // TODO: make it possible to use drop_in_place
-        if let Some(volatile) = self.try_get() {
+        if let Ok(volatile) = self.try_get() {
             volatile.read_volatile();
         }
-        ALLOCATOR.free_bytes(self.address);
+        if let Ok(alloc) = self.allocator() {
+            let _ = alloc.free_bytes(self.address);
+        }
     }
 }
 
+#[cfg(test)]
+use crate::unsafe_code::TestHeap;
+#[cfg(test)]
+use crate::SharedRc;
+#[cfg(test)]
+use num_traits::ToPrimitive;
+#[cfg(test)]
+use std::sync::atomic::AtomicI8;
+#[cfg(test)]
+use std::sync::atomic::AtomicU16;
+#[cfg(test)]
+use std::sync::atomic::AtomicU32;
+#[cfg(test)]
+use std::sync::atomic::AtomicU64;
 #[cfg(test)]
 use std::sync::atomic::AtomicUsize;
 #[cfg(test)]
@@ -133,3 +193,61 @@ fn test_five_boxes() {
         assert_eq!(val, i + 1);
     }
 }
+
+#[test]
+fn test_std_boxes() {
+    let boxed = SharedBox::new(AtomicU32::new(37));
+    assert_eq!(boxed.load(Ordering::SeqCst), 37);
+    let boxed: SharedBox<[AtomicU64; 16]> = SharedBox::new(Default::default());
+    boxed[15].store(37, Ordering::SeqCst);
+    assert_eq!(boxed[15].load(Ordering::SeqCst), 37);
+    let boxed = SharedBox::new([Volatile::new(1u8), Volatile::new(2u8)]);
+    assert_eq!(boxed[1].read_volatile(), 2);
+    let boxed = SharedBox::new((
+        AtomicI8::new(1),
+        AtomicU16::new(2),
+        PhantomData::<String>,
+        (AtomicU32::new(4),),
+    ));
+    assert_eq!(boxed.0.load(Ordering::SeqCst), 1);
+    assert_eq!(boxed.1.load(Ordering::SeqCst), 2);
+    assert_eq!((boxed.3).0.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn test_box_in() {
+    let alloc = TestHeap::new();
+    let boxed: SharedBox<AtomicUsize> = SharedBox::new_in(AtomicUsize::new(37), &alloc);
+    let val = boxed.load(Ordering::SeqCst);
+    assert_eq!(val, 37);
+    // The box is the only object in its slab,
+    // which is released when the box is dropped.
+    let address = boxed.address();
+    drop(boxed);
+    assert!(alloc.get_bytes(address).is_err());
+}
+
+#[test]
+fn test_box_size() {
+    // The type tag is kept in the object header, so small boxes don't pay extra for it
+    let boxed = SharedBox::new(AtomicU64::new(37));
+    assert_eq!(boxed.address().object_size().to_usize(), Some(32));
+}
+
+#[cfg(test)]
+#[derive(SharedMemCast, SharedMemRef)]
+#[repr(C)]
+struct TestPair<T> {
+    first: AtomicUsize,
+    second: T,
+}
+
+#[test]
+fn test_derived_box() {
+    let boxed = SharedBox::new(TestPair {
+        first: AtomicUsize::new(1),
+        second: SharedRc::new(AtomicUsize::new(2)),
+    });
+    assert_eq!(boxed.first.load(Ordering::SeqCst), 1);
+    assert_eq!(boxed.second.load(Ordering::SeqCst), 2);
+}
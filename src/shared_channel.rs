@@ -2,46 +2,119 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use crate::shared_futex::SharedFutex;
+use crate::SharedDataError;
+use crate::SharedMemCast;
 use crate::SharedOption;
 use crate::SharedRc;
 use crate::SharedVec;
+use crate::ShmemAllocator;
 use crate::Volatile;
 use crate::ALLOCATOR;
 use log::debug;
-use shared_memory::EventState;
-use shared_memory::SharedMemCast;
-use shared_memory::Timeout;
+use std::error::Error;
+use std::fmt;
 use std::ops::Deref;
+use std::sync::atomic::AtomicU8;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 pub(crate) struct SharedChannel<T: SharedMemCast> {
+    // Shared with any channels this grows into.
+    counts: SharedRc<SharedChannelCounts>,
     buffer: SharedVec<SharedOption<T>>,
     start: AtomicUsize,
     finish: AtomicUsize,
     // Initially none, but set to be the channel if it grows.
     grown: SharedOption<SharedRc<SharedChannel<T>>>,
-    // TODO: condition variable
+    // Notified when data is sent, or the channel grows.
+    event: SharedFutex,
+    // Bounded channels never grow, senders wait for space instead.
+    // This is non-zero for bounded channels, and is never changed once the channel is made,
+    // but it's atomic since it's shared memory, so another process could write any byte.
+    bounded: AtomicU8,
+    // Notified when data is received from a bounded channel.
+    space: SharedFutex,
+}
+
+// The number of live senders and receivers.
+pub(crate) struct SharedChannelCounts {
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
 }
 
 impl<T: SharedMemCast> SharedChannel<T> {
-    fn try_new(capacity: usize) -> Option<SharedChannel<T>> {
-        Some(SharedChannel {
-            buffer: SharedVec::try_from_iter((0..capacity).map(|_| SharedOption::none()))?,
+    fn try_new_in(
+        capacity: usize,
+        bounded: bool,
+        counts: SharedRc<SharedChannelCounts>,
+        alloc: &ShmemAllocator,
+    ) -> Result<SharedChannel<T>, SharedDataError> {
+        Ok(SharedChannel {
+            counts,
+            buffer: SharedVec::try_from_iter_in(
+                (0..capacity).map(|_| SharedOption::none()),
+                alloc,
+            )?,
             start: AtomicUsize::new(0),
             finish: AtomicUsize::new(0),
             grown: SharedOption::none(),
+            event: SharedFutex::new(),
+            bounded: AtomicU8::new(bounded as u8),
+            space: SharedFutex::new(),
         })
     }
+
+    fn is_bounded(&self) -> bool {
+        self.bounded.load(Ordering::SeqCst) != 0
+    }
+
+    // Whether a bounded channel has no room for another message.
+    fn is_full(&self) -> bool {
+        let start = self.start.load(Ordering::SeqCst);
+        let finish = self.finish.load(Ordering::SeqCst);
+        finish.wrapping_sub(start) >= self.buffer.len()
+    }
+
+    // Wake up any receivers waiting on this channel, or any channel it has grown into.
+    fn notify_all(&self) {
+        let mut this = self;
+        loop {
+            this.event.notify_all();
+            match this.grown.volatile_peek() {
+                Some(grown) => this = grown,
+                None => return,
+            }
+        }
+    }
 }
 
-#[derive(Clone)]
+/// The sending half of a channel in shared memory, which can be passed to another process.
+///
+/// Blocking sends on a bounded channel wait in the same way as `SharedReceiver::recv`.
 pub struct SharedSender<T: SharedMemCast>(SharedRc<SharedChannel<T>>);
 
 impl<T: SharedMemCast> SharedSender<T> {
-    pub fn try_send(&mut self, mut data: T) -> Result<(), T> {
+    /// Sends data on the channel, without blocking.
+    ///
+    /// Returns an error if the receiver has been dropped, or the channel could not grow.
+    pub fn try_send(&mut self, mut data: T) -> Result<(), TrySendError<T>> {
         loop {
+            if self.0.counts.receivers.load(Ordering::SeqCst) == 0 {
+                debug!("Receiver disconnected");
+                return Err(TrySendError::Disconnected(data));
+            }
+            if self.0.is_bounded() {
+                return self.try_send_bounded(data);
+            }
             let capacity = self.0.buffer.len();
+            let alloc = match SharedRc::allocator(&self.0) {
+                Ok(alloc) => alloc,
+                Err(_) => return Err(TrySendError::Full(data)),
+            };
             if let Some(grown) = self.0.grown.volatile_peek() {
                 debug!("Sending to grown channel");
                 self.0 = grown.deref().clone();
@@ -49,33 +122,139 @@ impl<T: SharedMemCast> SharedSender<T> {
             }
             let index = self.0.finish.fetch_add(1, Ordering::SeqCst);
             if let Err(unsent) = self.0.buffer[index % capacity].put(data) {
-                if let Some(grown) = SharedChannel::try_new(capacity * 2) {
+                let counts = self.0.counts.clone();
+                if let Ok(grown) = SharedChannel::try_new_in(capacity * 2, false, counts, alloc) {
                     debug!("Growing channel");
                     self.0.finish.fetch_sub(1, Ordering::SeqCst);
-                    let _ = self.0.grown.put(SharedRc::new(grown));
+                    let _ = self.0.grown.put(SharedRc::new_in(grown, alloc));
+                    self.0.event.notify_all();
                     data = unsent;
                     continue;
                 } else {
                     debug!("Failed to grow channel");
-                    return Err(unsent);
+                    return Err(TrySendError::Full(unsent));
                 }
             }
-            // TODO: don't use a global condition variable!
             debug!("Wake up receiver");
-            ALLOCATOR.set_event(EventState::Signaled);
+            self.0.notify_all();
             return Ok(());
         }
     }
 
-    pub fn send(&mut self, data: T) {
-        self.try_send(data).ok().expect("Sending data failed");
+    // Bounded channels reserve a slot by moving `finish` forward, but only while it is
+    // less than `capacity` ahead of `start`, so senders never overfill the buffer.
+    // The counters are never wrapped round, since that would race with the reservation.
+    fn try_send_bounded(&mut self, mut data: T) -> Result<(), TrySendError<T>> {
+        let capacity = self.0.buffer.len();
+        let index = loop {
+            let index = self.0.finish.load(Ordering::SeqCst);
+            let start = self.0.start.load(Ordering::SeqCst);
+            if index.wrapping_sub(start) >= capacity {
+                if index == self.0.finish.load(Ordering::SeqCst) {
+                    debug!("Channel is full");
+                    return Err(TrySendError::Full(data));
+                }
+                continue;
+            }
+            if self
+                .0
+                .finish
+                .compare_exchange(
+                    index,
+                    index.wrapping_add(1),
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .is_ok()
+            {
+                break index;
+            }
+        };
+        // The receiver which moved `start` past this slot may still be emptying it.
+        while let Err(unsent) = self.0.buffer[index % capacity].put(data) {
+            data = unsent;
+            thread::yield_now();
+        }
+        debug!("Wake up receiver");
+        self.0.notify_all();
+        Ok(())
+    }
+
+    /// Sends data on the channel, waiting for space if the channel is bounded.
+    ///
+    /// Returns an error if the receiver has been dropped,
+    /// and panics if an unbounded channel could not grow.
+    pub fn send(&mut self, mut data: T) -> Result<(), SendError<T>> {
+        loop {
+            match self.try_send(data) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(unsent)) => return Err(SendError(unsent)),
+                Err(TrySendError::Full(unsent)) if self.0.is_bounded() => {
+                    debug!("Waiting for receiver");
+                    data = unsent;
+                    self.wait_for_space();
+                }
+                Err(TrySendError::Full(_)) => panic!("Sending data failed"),
+            }
+        }
+    }
+
+    // Waits until there might be space in a bounded channel, or the receiver is dropped.
+    fn wait_for_space(&self) {
+        let value = self.0.space.load();
+        if self.0.is_full() && self.0.counts.receivers.load(Ordering::SeqCst) != 0 {
+            self.0.space.wait(value, None);
+        }
+    }
+}
+
+impl<T: SharedMemCast> Clone for SharedSender<T> {
+    fn clone(&self) -> Self {
+        self.0.counts.senders.fetch_add(1, Ordering::SeqCst);
+        SharedSender(self.0.clone())
+    }
+}
+
+impl<T: SharedMemCast> Drop for SharedSender<T> {
+    fn drop(&mut self) {
+        if self.0.counts.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            debug!("Last sender dropped, wake up receiver");
+            self.0.notify_all();
+        }
     }
 }
 
+/// The receiving half of a channel in shared memory, which can be passed to another process.
+///
+/// On Linux, a receiver waiting for data blocks on a futex until a sender notifies it.
+/// Other platforms have no futex that works between processes, so a waiting receiver
+/// sleeps for a millisecond at a time and checks the channel again, which adds up to
+/// a millisecond of latency to each message it waits for.
 pub struct SharedReceiver<T: SharedMemCast>(SharedRc<SharedChannel<T>>);
 
 impl<T: SharedMemCast> SharedReceiver<T> {
-    pub fn try_recv(&mut self) -> Option<T> {
+    /// Receives data from the channel, without blocking.
+    ///
+    /// Returns an error if there is no data, or every sender has been dropped.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(result) = self.take() {
+            Ok(result)
+        } else if self.is_disconnected() {
+            // The senders may have sent data before being dropped
+            self.take().ok_or(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.0.counts.senders.load(Ordering::SeqCst) == 0
+    }
+
+    fn take(&mut self) -> Option<T> {
+        if self.0.is_bounded() {
+            return self.take_bounded();
+        }
         loop {
             let capacity = self.0.buffer.len();
             let index = self.0.start.fetch_add(1, Ordering::SeqCst);
@@ -100,6 +279,89 @@ impl<T: SharedMemCast> SharedReceiver<T> {
         }
     }
 
+    // Bounded channels only move `start` past a slot once it is full,
+    // so the senders can use it to tell how much space there is.
+    fn take_bounded(&mut self) -> Option<T> {
+        let capacity = self.0.buffer.len();
+        loop {
+            let index = self.0.start.load(Ordering::SeqCst);
+            let slot = &self.0.buffer[index % capacity];
+            slot.volatile_peek()?;
+            if self
+                .0
+                .start
+                .compare_exchange(
+                    index,
+                    index.wrapping_add(1),
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .is_ok()
+            {
+                debug!("Received data");
+                let result = slot.take();
+                self.0.space.notify_all();
+                return result;
+            }
+        }
+    }
+
+    /// Waits for data on the channel.
+    ///
+    /// Returns an error if every sender has been dropped.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(result) => return Ok(result),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => (),
+            }
+            debug!("Waiting for sender");
+            self.wait(None);
+        }
+    }
+
+    /// Waits for data on the channel, giving up after `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.recv_deadline(deadline),
+            None => self.recv().map_err(RecvTimeoutError::from),
+        }
+    }
+
+    /// Waits for data on the channel, giving up at `deadline`.
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        loop {
+            match self.try_recv() {
+                Ok(result) => return Ok(result),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => (),
+            }
+            let now = Instant::now();
+            if deadline <= now {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            debug!("Waiting for sender for {:?}", deadline - now);
+            self.wait(Some(deadline - now));
+        }
+    }
+
+    // Waits until there might be data, the senders are dropped, or the timeout expires.
+    fn wait(&self, timeout: Option<Duration>) {
+        // Senders are sending to the end of the chain of grown channels.
+        let mut this: &SharedChannel<T> = &self.0;
+        while let Some(grown) = this.grown.volatile_peek() {
+            this = grown;
+        }
+        let value = this.event.load();
+        if self.try_peek().is_none()
+            && this.grown.volatile_peek().is_none()
+            && !self.is_disconnected()
+        {
+            this.event.wait(value, timeout);
+        }
+    }
+
     pub fn try_peek(&self) -> Option<&Volatile<T>> {
         let mut this = &self.0;
         loop {
@@ -126,45 +388,306 @@ impl<T: SharedMemCast> SharedReceiver<T> {
         }
     }
 
-    pub fn peek(&self) -> &Volatile<T> {
+    /// Waits for data on the channel, without removing it.
+    ///
+    /// Returns an error if every sender has been dropped.
+    pub fn peek(&self) -> Result<&Volatile<T>, RecvError> {
         loop {
             if let Some(result) = self.try_peek() {
-                return result;
+                return Ok(result);
+            } else if self.is_disconnected() {
+                // The senders may have sent data before being dropped
+                return self.try_peek().ok_or(RecvError);
             } else {
-                // TODO: don't use a global condition variable!
                 debug!("Waiting for sender");
-                ALLOCATOR.wait_event(Timeout::Infinite);
+                self.wait(None);
             }
         }
     }
 }
 
-pub fn channel<T: SharedMemCast>() -> Option<(SharedSender<T>, SharedReceiver<T>)> {
-    let channel = SharedRc::try_new(SharedChannel::try_new(1)?)?;
-    Some((SharedSender(channel.clone()), SharedReceiver(channel)))
+impl<T: SharedMemCast> Drop for SharedReceiver<T> {
+    fn drop(&mut self) {
+        self.0.counts.receivers.fetch_sub(1, Ordering::SeqCst);
+        if self.0.is_bounded() {
+            debug!("Receiver dropped, wake up senders");
+            self.0.space.notify_all();
+        }
+    }
 }
 
-#[cfg(test)]
-use std::thread;
+/// An error returned from `SharedSender::send`, containing the unsent data.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct SendError<T>(pub T);
 
-#[cfg(test)]
-use std::time::Duration;
+/// An error returned from `SharedSender::try_send`, containing the unsent data.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum TrySendError<T> {
+    /// The channel is full.
+    Full(T),
+    /// The receiver has been dropped.
+    Disconnected(T),
+}
+
+/// An error returned from `SharedReceiver::try_recv`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TryRecvError {
+    /// There is no data in the channel.
+    Empty,
+    /// Every sender has been dropped.
+    Disconnected,
+}
+
+/// An error returned from `SharedReceiver::recv`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RecvError;
+
+/// An error returned from `SharedReceiver::recv_timeout` and `SharedReceiver::recv_deadline`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RecvTimeoutError {
+    /// No data arrived before the timeout.
+    Timeout,
+    /// Every sender has been dropped.
+    Disconnected,
+}
+
+impl From<RecvError> for RecvTimeoutError {
+    fn from(_: RecvError) -> RecvTimeoutError {
+        RecvTimeoutError::Disconnected
+    }
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SendError(..)")
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "Full(..)"),
+            TrySendError::Disconnected(_) => write!(f, "Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sending on a closed channel")
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "sending on a full channel"),
+            TrySendError::Disconnected(_) => write!(f, "sending on a closed channel"),
+        }
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "receiving on an empty channel"),
+            TryRecvError::Disconnected => write!(f, "receiving on a closed channel"),
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "receiving on a closed channel")
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => write!(f, "timed out waiting on channel"),
+            RecvTimeoutError::Disconnected => write!(f, "receiving on a closed channel"),
+        }
+    }
+}
+
+impl<T> Error for SendError<T> {}
+impl<T> Error for TrySendError<T> {}
+impl Error for TryRecvError {}
+impl Error for RecvError {}
+impl Error for RecvTimeoutError {}
+
+fn new_channel_in<T: SharedMemCast>(
+    capacity: usize,
+    bounded: bool,
+    alloc: &ShmemAllocator,
+) -> Result<(SharedSender<T>, SharedReceiver<T>), SharedDataError> {
+    let counts = SharedChannelCounts {
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+    };
+    let counts = SharedRc::try_new_in(counts, alloc)?;
+    let channel = SharedChannel::try_new_in(capacity, bounded, counts, alloc)?;
+    let channel = SharedRc::try_new_in(channel, alloc)?;
+    Ok((SharedSender(channel.clone()), SharedReceiver(channel)))
+}
+
+pub fn channel_in<T: SharedMemCast>(
+    alloc: &ShmemAllocator,
+) -> Result<(SharedSender<T>, SharedReceiver<T>), SharedDataError> {
+    new_channel_in(1, false, alloc)
+}
+
+pub fn channel<T: SharedMemCast>() -> Result<(SharedSender<T>, SharedReceiver<T>), SharedDataError>
+{
+    channel_in(*ALLOCATOR)
+}
+
+/// Creates a channel which holds at most `capacity` messages (rounded up to 1).
+pub fn sync_channel_in<T: SharedMemCast>(
+    capacity: usize,
+    alloc: &ShmemAllocator,
+) -> Result<(SharedSender<T>, SharedReceiver<T>), SharedDataError> {
+    new_channel_in(capacity.max(1), true, alloc)
+}
+
+/// Creates a channel which holds at most `capacity` messages (rounded up to 1).
+pub fn sync_channel<T: SharedMemCast>(
+    capacity: usize,
+) -> Result<(SharedSender<T>, SharedReceiver<T>), SharedDataError> {
+    sync_channel_in(capacity, *ALLOCATOR)
+}
 
 #[test]
 fn test_channels() {
     let (mut sender, mut receiver) = channel().unwrap();
     thread::spawn(move || {
         thread::sleep(Duration::from_millis(10));
-        sender.send(AtomicUsize::new(5));
+        sender.send(AtomicUsize::new(5)).unwrap();
         thread::sleep(Duration::from_millis(10));
-        sender.send(AtomicUsize::new(37));
-        sender.send(AtomicUsize::new(2));
+        sender.send(AtomicUsize::new(37)).unwrap();
+        sender.send(AtomicUsize::new(2)).unwrap();
     });
-    assert_eq!(receiver.peek().load(Ordering::SeqCst), 5);
+    assert_eq!(receiver.peek().unwrap().load(Ordering::SeqCst), 5);
     assert_eq!(receiver.try_recv().unwrap().load(Ordering::SeqCst), 5);
-    assert_eq!(receiver.peek().load(Ordering::SeqCst), 37);
+    assert_eq!(receiver.peek().unwrap().load(Ordering::SeqCst), 37);
     assert_eq!(receiver.try_recv().unwrap().load(Ordering::SeqCst), 37);
     thread::sleep(Duration::from_millis(10));
-    assert_eq!(receiver.peek().load(Ordering::SeqCst), 2);
+    assert_eq!(receiver.peek().unwrap().load(Ordering::SeqCst), 2);
     assert_eq!(receiver.try_recv().unwrap().load(Ordering::SeqCst), 2);
 }
+
+#[test]
+fn test_recv() {
+    let (mut sender, mut receiver) = channel().unwrap();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        sender.send(AtomicUsize::new(5)).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        sender.send(AtomicUsize::new(37)).unwrap();
+    });
+    assert_eq!(receiver.recv().unwrap().load(Ordering::SeqCst), 5);
+    assert_eq!(receiver.recv().unwrap().load(Ordering::SeqCst), 37);
+}
+
+#[test]
+fn test_recv_timeout() {
+    let (mut sender, mut receiver) = channel::<AtomicUsize>().unwrap();
+    let timeout = Duration::from_millis(10);
+    let start = Instant::now();
+    assert_eq!(
+        receiver.recv_timeout(timeout).err(),
+        Some(RecvTimeoutError::Timeout)
+    );
+    assert!(start.elapsed() >= timeout);
+    sender.send(AtomicUsize::new(37)).unwrap();
+    let val = receiver.recv_timeout(timeout).unwrap();
+    assert_eq!(val.load(Ordering::SeqCst), 37);
+}
+
+#[test]
+fn test_sender_disconnect() {
+    let (mut sender, mut receiver) = channel().unwrap();
+    let mut sender2 = sender.clone();
+    thread::spawn(move || {
+        sender.send(AtomicUsize::new(5)).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        sender2.send(AtomicUsize::new(37)).unwrap();
+    });
+    assert_eq!(receiver.recv().unwrap().load(Ordering::SeqCst), 5);
+    assert_eq!(receiver.recv().unwrap().load(Ordering::SeqCst), 37);
+    assert_eq!(receiver.recv().err(), Some(RecvError));
+    assert_eq!(receiver.peek().err(), Some(RecvError));
+    assert_eq!(receiver.try_recv().err(), Some(TryRecvError::Disconnected));
+}
+
+#[test]
+fn test_receiver_disconnect() {
+    let (mut sender, receiver) = channel().unwrap();
+    sender.send(AtomicUsize::new(5)).unwrap();
+    drop(receiver);
+    match sender.try_send(AtomicUsize::new(37)) {
+        Err(TrySendError::Disconnected(data)) => assert_eq!(data.load(Ordering::SeqCst), 37),
+        _ => panic!("Expected disconnection"),
+    }
+}
+
+#[test]
+fn test_sync_channel() {
+    let (mut sender, mut receiver) = sync_channel(2).unwrap();
+    sender.try_send(AtomicUsize::new(1)).unwrap();
+    sender.try_send(AtomicUsize::new(2)).unwrap();
+    match sender.try_send(AtomicUsize::new(3)) {
+        Err(TrySendError::Full(data)) => assert_eq!(data.load(Ordering::SeqCst), 3),
+        _ => panic!("Expected a full channel"),
+    }
+    let handle = thread::spawn(move || {
+        for i in 3..10 {
+            sender.send(AtomicUsize::new(i)).unwrap();
+        }
+    });
+    for i in 1..10 {
+        assert_eq!(receiver.recv().unwrap().load(Ordering::SeqCst), i);
+    }
+    handle.join().unwrap();
+    assert_eq!(receiver.recv().err(), Some(RecvError));
+}
+
+#[test]
+fn test_sync_channel_disconnect() {
+    let (mut sender, receiver) = sync_channel(1).unwrap();
+    sender.send(AtomicUsize::new(1)).unwrap();
+    let handle = thread::spawn(move || sender.send(AtomicUsize::new(2)).is_err());
+    thread::sleep(Duration::from_millis(10));
+    drop(receiver);
+    assert!(handle.join().unwrap());
+}
+
+#[test]
+fn test_sync_channel_stress() {
+    // Senders racing for the last slot must not overfill the buffer
+    let (sender, mut receiver) = sync_channel(2).unwrap();
+    let handles: Vec<_> = (0..4)
+        .map(|id| {
+            let mut sender = sender.clone();
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    sender.send(AtomicUsize::new(id * 1000 + i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+    let mut last = [None; 4];
+    for _ in 0..4000 {
+        let value = receiver.recv().unwrap().load(Ordering::SeqCst);
+        let (id, i) = (value / 1000, value % 1000);
+        // Each sender's messages arrive in order, with none lost or duplicated
+        assert_eq!(last[id].map_or(0, |last| last + 1), i);
+        last[id] = Some(i);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(receiver.recv().err(), Some(RecvError));
+}
@@ -0,0 +1,185 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::SharedAddressRange;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str::Utf8Error;
+
+/// The errors that can happen when using shared data.
+#[derive(Debug)]
+pub enum SharedDataError {
+    /// Shared memory could not be created or opened.
+    Shmem(Box<dyn Error + Send + Sync>),
+    /// The name of some shared memory is too long to be stored.
+    NameTooLong(String),
+    /// The allocator has run out of shared memory segments.
+    OutOfSegments,
+    /// This process has run out of room for allocators.
+    OutOfAllocators,
+    /// The allocator does not support objects this large.
+    TooLarge(usize),
+    /// The size in bytes of the allocation does not fit in a `usize`.
+    SizeOverflow(u128),
+    /// The allocator is not open in this process.
+    UnknownAllocator,
+    /// The address is not in shared memory used by the allocator.
+    UnknownSegment(SharedAddressRange),
+    /// The address is not within its shared memory.
+    OutOfBounds(SharedAddressRange),
+    /// The memory is too small for the data being stored in it.
+    TooSmall { size: usize, required: usize },
+    /// The shared memory is not valid UTF-8.
+    InvalidUtf8(Utf8Error),
+    /// No object has been published with this name.
+    NotPublished(String),
+    /// An object has already been published with this name.
+    AlreadyPublished(String),
+    /// The object published with this name has a different type.
+    TypeMismatch(String),
+    /// The allocator has run out of room for published objects.
+    OutOfRoots,
+    /// The address refers to an allocation of a different type.
+    WrongType(SharedAddressRange),
+    /// The address refers to an object which has been freed.
+    ///
+    /// Addresses only keep the low 7 bits of the object's generation, so this isn't
+    /// reported for an address whose memory has been reused a multiple of 128 times.
+    StaleAddress(SharedAddressRange),
+}
+
+impl fmt::Display for SharedDataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SharedDataError::Shmem(err) => write!(f, "shared memory error: {}", err),
+            SharedDataError::NameTooLong(name) => write!(f, "shared memory name {} too long", name),
+            SharedDataError::OutOfSegments => write!(f, "out of shared memory segments"),
+            SharedDataError::OutOfAllocators => write!(f, "out of shared memory allocators"),
+            SharedDataError::TooLarge(size) => write!(f, "allocation of {} bytes too large", size),
+            SharedDataError::SizeOverflow(size) => {
+                write!(f, "allocation of {} bytes overflows", size)
+            }
+            SharedDataError::UnknownAllocator => write!(f, "unknown shared memory allocator"),
+            SharedDataError::UnknownSegment(address) => {
+                write!(f, "address {:?} in unknown shared memory", address)
+            }
+            SharedDataError::OutOfBounds(address) => {
+                write!(f, "address {:?} out of bounds", address)
+            }
+            SharedDataError::TooSmall { size, required } => write!(
+                f,
+                "{} bytes of shared memory too small, {} required",
+                size, required
+            ),
+            SharedDataError::InvalidUtf8(err) => write!(f, "invalid shared string: {}", err),
+            SharedDataError::NotPublished(name) => write!(f, "{} has not been published", name),
+            SharedDataError::AlreadyPublished(name) => {
+                write!(f, "{} has already been published", name)
+            }
+            SharedDataError::TypeMismatch(name) => {
+                write!(f, "{} was published with a different type", name)
+            }
+            SharedDataError::OutOfRoots => write!(f, "out of room for published objects"),
+            SharedDataError::WrongType(address) => {
+                write!(f, "address {:?} has the wrong type", address)
+            }
+            SharedDataError::StaleAddress(address) => {
+                write!(f, "address {:?} has been freed", address)
+            }
+        }
+    }
+}
+
+impl SharedDataError {
+    // The errors from the shared memory crate aren't `Send` or `Sync`, so unless
+    // they are I/O errors, we copy them along with their sources.
+    pub(crate) fn shmem(err: Box<dyn Error>) -> SharedDataError {
+        match err.downcast::<io::Error>() {
+            Ok(err) => SharedDataError::Shmem(err),
+            Err(err) => SharedDataError::Shmem(Box::new(ShmemError::new(&*err))),
+        }
+    }
+}
+
+/// A copy of an error from the shared memory crate, which keeps its sources.
+#[derive(Debug)]
+struct ShmemError {
+    message: String,
+    source: Option<Box<ShmemError>>,
+}
+
+impl ShmemError {
+    fn new(err: &dyn Error) -> ShmemError {
+        ShmemError {
+            message: err.to_string(),
+            source: err.source().map(|source| Box::new(ShmemError::new(source))),
+        }
+    }
+}
+
+impl fmt::Display for ShmemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ShmemError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn Error + 'static))
+    }
+}
+
+impl Error for SharedDataError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SharedDataError::Shmem(err) => Some(&**err),
+            SharedDataError::InvalidUtf8(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_send_sync() {
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<SharedDataError>();
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct TestError(Option<io::Error>);
+
+#[cfg(test)]
+impl fmt::Display for TestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("mapping failed")
+    }
+}
+
+#[cfg(test)]
+impl Error for TestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.0.as_ref().map(|err| err as &(dyn Error + 'static))
+    }
+}
+
+#[test]
+fn test_shmem_source() {
+    // I/O errors are kept as they are
+    let err = SharedDataError::shmem(Box::new(io::Error::from(io::ErrorKind::NotFound)));
+    let source = err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+    assert_eq!(source.kind(), io::ErrorKind::NotFound);
+    // Other errors are copied, along with their sources
+    let cause = io::Error::other("out of file descriptors");
+    let err = SharedDataError::shmem(Box::new(TestError(Some(cause))));
+    assert_eq!(err.to_string(), "shared memory error: mapping failed");
+    let source = err.source().unwrap();
+    assert_eq!(source.to_string(), "mapping failed");
+    let cause = source.source().unwrap();
+    assert_eq!(cause.to_string(), "out of file descriptors");
+    assert!(cause.source().is_none());
+}
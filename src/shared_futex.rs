@@ -0,0 +1,101 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+pub(crate) use crate::unsafe_code::futex_wait;
+#[cfg(target_os = "linux")]
+pub(crate) use crate::unsafe_code::futex_wake;
+
+#[cfg(not(target_os = "linux"))]
+use std::thread;
+
+// Elsewhere there are no futexes that work between processes, so we poll,
+// which the docs of `SharedMutex` and `SharedReceiver` warn about.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn futex_wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let poll = Duration::from_millis(1);
+    if futex.load(Ordering::SeqCst) == expected {
+        thread::sleep(timeout.map_or(poll, |timeout| Duration::min(timeout, poll)));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn futex_wake(_futex: &AtomicU32, _count: i32) {}
+
+/// Somewhere in shared memory that processes can wait to be notified.
+pub(crate) struct SharedFutex {
+    // Incremented every time the futex is notified
+    value: AtomicU32,
+    // The number of processes waiting, so notifying is cheap when nobody is waiting
+    waiters: AtomicU32,
+}
+
+impl SharedFutex {
+    pub fn new() -> SharedFutex {
+        SharedFutex {
+            value: AtomicU32::new(0),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    /// The current value of the futex, to be passed to `wait`.
+    ///
+    /// This should be read before checking whatever condition is being waited for,
+    /// so that a notification in between is not lost.
+    pub fn load(&self) -> u32 {
+        self.value.load(Ordering::SeqCst)
+    }
+
+    /// Waits to be notified, unless the futex has been notified since `value` was loaded.
+    ///
+    /// Wakeups may be spurious.
+    pub fn wait(&self, value: u32, timeout: Option<Duration>) {
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        futex_wait(&self.value, value, timeout);
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Wakes up everyone waiting on the futex.
+    pub fn notify_all(&self) {
+        self.value.fetch_add(1, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) != 0 {
+            futex_wake(&self.value, i32::MAX);
+        }
+    }
+}
+
+#[cfg(test)]
+use std::sync::Arc;
+#[cfg(test)]
+use std::thread;
+#[cfg(test)]
+use std::time::Instant;
+
+#[test]
+fn test_futex() {
+    let futex = Arc::new(SharedFutex::new());
+    // Waiting with a stale value returns immediately
+    let value = futex.load();
+    futex.notify_all();
+    futex.wait(value, None);
+    // Waiting times out
+    let timeout = Duration::from_millis(10);
+    let start = Instant::now();
+    futex.wait(futex.load(), Some(timeout));
+    assert!(start.elapsed() >= timeout);
+    // Waiting gets notified
+    let value = futex.load();
+    let notifier = futex.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        notifier.notify_all();
+    });
+    while futex.load() == value {
+        futex.wait(value, None);
+    }
+}
@@ -0,0 +1,617 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::shared_futex::SharedFutex;
+use crate::unsafe_code;
+use crate::AllocatorId;
+use crate::AtomicSharedAddressRange;
+use crate::Reading;
+use crate::SharedAddressRange;
+use crate::SharedDataError;
+use crate::SharedMemCast;
+use crate::ShmemAllocator;
+use crate::Volatile;
+use crate::ALLOCATOR;
+use log::debug;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::marker::PhantomData;
+use std::mem;
+use std::mem::ManuallyDrop;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+const MIN_CAPACITY: usize = 8;
+
+// The states of a slot. Slots are never empty again once they have been used,
+// until the map is resized.
+const EMPTY: u8 = 0;
+const RESERVED: u8 = 1;
+const OCCUPIED: u8 = 2;
+const REMOVING: u8 = 3;
+const REMOVED: u8 = 4;
+
+// Set in the state of the map while it is being resized.
+const RESIZING: usize = 1 << (usize::MAX.count_ones() - 1);
+
+/// A concurrent hash map in shared memory.
+///
+/// Entries can be inserted, got and removed concurrently, but some operations wait for others:
+///
+/// * inserting spins while it passes a slot whose key is still being written,
+/// * removing an entry spins until everyone reading that entry has finished, and
+/// * resizing waits for every other operation to finish, and blocks new ones until it is done.
+///
+/// Keys are hashed with a fixed hasher rather than `DefaultHasher`, whose algorithm may
+/// change between Rust releases, so processes built with different compilers agree on
+/// where each key goes. The hasher reads bytes as little-endian words, so the hashes are
+/// the same on every architecture too.
+pub struct SharedHashMap<K: SharedMemCast, V: SharedMemCast> {
+    buffer: AtomicSharedAddressRange,
+    capacity: AtomicUsize,
+    allocator_id: AllocatorId,
+    // The number of entries.
+    len: AtomicUsize,
+    // The number of slots which are not empty, including removed entries.
+    used: AtomicUsize,
+    // The number of operations in progress, and whether the map is being resized.
+    state: AtomicUsize,
+    // Notified when an operation finishes during a resize, or a resize finishes.
+    gate: SharedFutex,
+    marker: PhantomData<(K, V)>,
+}
+
+pub(crate) struct SharedHashMapSlot<K: SharedMemCast, V: SharedMemCast> {
+    state: AtomicU8,
+    // The number of processes accessing the key or value.
+    readers: AtomicUsize,
+    hash: AtomicU64,
+    key: Volatile<K>,
+    value: Volatile<V>,
+}
+
+impl<K: SharedMemCast, V: SharedMemCast> SharedHashMapSlot<K, V> {
+    // Access the slot, as long as it is occupied.
+    fn read<R>(&self, f: impl FnOnce(&Self) -> R) -> Option<R> {
+        let _reading = Reading::new(&self.readers);
+        if self.state.load(Ordering::SeqCst) == OCCUPIED {
+            Some(f(self))
+        } else {
+            None
+        }
+    }
+
+    // Take the entry out of the slot, once nobody is reading it.
+    fn take(&self) -> Option<(K, V)> {
+        if self
+            .state
+            .compare_exchange(OCCUPIED, REMOVING, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return None;
+        }
+        while self.readers.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+        let entry = (self.key.read_volatile(), self.value.read_volatile());
+        self.state.store(REMOVED, Ordering::SeqCst);
+        Some(entry)
+    }
+}
+
+impl<K: SharedMemCast + Hash + Eq, V: SharedMemCast> SharedHashMapSlot<K, V> {
+    // Only call this while reading the slot.
+    fn has_key(&self, hash: u64, key: &K) -> bool {
+        // The key stays put while the slot is being read, so comparing a copy is fine,
+        // as long as the copy isn't dropped.
+        self.hash.load(Ordering::SeqCst) == hash
+            && *ManuallyDrop::new(self.key.read_volatile()) == *key
+    }
+}
+
+// Stops the map from being resized while it is alive.
+struct Entered<'a, K: SharedMemCast, V: SharedMemCast>(&'a SharedHashMap<K, V>);
+
+impl<'a, K: SharedMemCast, V: SharedMemCast> Drop for Entered<'a, K, V> {
+    fn drop(&mut self) {
+        self.0.exit();
+    }
+}
+
+impl<K: SharedMemCast, V: SharedMemCast> SharedHashMap<K, V> {
+    fn enter(&self) -> Entered<'_, K, V> {
+        loop {
+            let value = self.gate.load();
+            let state = self.state.load(Ordering::SeqCst);
+            if state & RESIZING != 0 {
+                debug!("Waiting for resize");
+                self.gate.wait(value, None);
+            } else if self
+                .state
+                .compare_exchange(state, state + 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return Entered(self);
+            }
+        }
+    }
+
+    fn exit(&self) {
+        if self.state.fetch_sub(1, Ordering::SeqCst) & RESIZING != 0 {
+            self.gate.notify_all();
+        }
+    }
+
+    fn allocator(&self) -> Result<&'static ShmemAllocator, SharedDataError> {
+        ShmemAllocator::from_id(self.allocator_id)
+    }
+
+    // Only call this while entered, so the buffer isn't freed by a resize.
+    fn slots(&self) -> Result<&[SharedHashMapSlot<K, V>], SharedDataError> {
+        let address = self.buffer.load(Ordering::SeqCst);
+        let capacity = self.capacity.load(Ordering::SeqCst);
+        slots_from_address(self.allocator()?, address, capacity)
+    }
+
+    /// The number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of entries the map can hold without resizing.
+    pub fn capacity(&self) -> usize {
+        max_used(self.capacity.load(Ordering::SeqCst))
+    }
+}
+
+impl<K: SharedMemCast + Hash + Eq, V: SharedMemCast> SharedHashMap<K, V> {
+    pub fn try_new_in(alloc: &ShmemAllocator) -> Result<SharedHashMap<K, V>, SharedDataError> {
+        let buffer = alloc_slots::<K, V>(alloc, MIN_CAPACITY)?;
+        Ok(SharedHashMap {
+            buffer: AtomicSharedAddressRange::new(buffer),
+            capacity: AtomicUsize::new(MIN_CAPACITY),
+            allocator_id: alloc.id(),
+            len: AtomicUsize::new(0),
+            used: AtomicUsize::new(0),
+            state: AtomicUsize::new(0),
+            gate: SharedFutex::new(),
+            marker: PhantomData,
+        })
+    }
+
+    pub fn new_in(alloc: &ShmemAllocator) -> SharedHashMap<K, V> {
+        SharedHashMap::try_new_in(alloc).expect("Failed to allocate shared hash map")
+    }
+
+    pub fn try_new() -> Result<SharedHashMap<K, V>, SharedDataError> {
+        SharedHashMap::try_new_in(*ALLOCATOR)
+    }
+
+    pub fn new() -> SharedHashMap<K, V> {
+        SharedHashMap::try_new().expect("Failed to allocate shared hash map")
+    }
+
+    /// Inserts an entry, returning the previous value for the key,
+    /// or an error if the map could not be resized.
+    pub fn try_insert(&self, key: K, value: V) -> Result<Option<V>, SharedDataError> {
+        let hash = hash_of(&key);
+        let mut entered = self.enter();
+        // Make sure there will be an empty slot for the entry.
+        while self.used.fetch_add(1, Ordering::SeqCst) >= self.capacity() {
+            self.used.fetch_sub(1, Ordering::SeqCst);
+            entered = self.try_resize(entered)?;
+        }
+        let slots = match self.slots() {
+            Ok(slots) => slots,
+            Err(err) => {
+                self.used.fetch_sub(1, Ordering::SeqCst);
+                return Err(err);
+            }
+        };
+        let mut result = None;
+        let mut index = hash as usize % slots.len();
+        loop {
+            let slot = &slots[index];
+            match slot.state.load(Ordering::SeqCst) {
+                EMPTY => {
+                    if slot
+                        .state
+                        .compare_exchange(EMPTY, RESERVED, Ordering::SeqCst, Ordering::SeqCst)
+                        .is_ok()
+                    {
+                        slot.hash.store(hash, Ordering::SeqCst);
+                        slot.key.write_volatile(key);
+                        slot.value.write_volatile(value);
+                        slot.state.store(OCCUPIED, Ordering::SeqCst);
+                        self.len.fetch_add(1, Ordering::SeqCst);
+                        return Ok(result);
+                    }
+                    continue;
+                }
+                // Wait for the key to be written, in case it's this key.
+                RESERVED => {
+                    thread::yield_now();
+                    continue;
+                }
+                OCCUPIED if slot.read(|slot| slot.has_key(hash, &key)) == Some(true) => {
+                    if let Some((_, old)) = slot.take() {
+                        self.len.fetch_sub(1, Ordering::SeqCst);
+                        result = Some(old);
+                    }
+                }
+                _ => (),
+            }
+            index = (index + 1) % slots.len();
+        }
+    }
+
+    /// Inserts an entry, returning the previous value for the key,
+    /// and panicing if the map could not be resized.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.try_insert(key, value)
+            .expect("Failed to insert into shared hash map")
+    }
+
+    /// Calls `f` on the value for the key, if there is one.
+    pub fn get_with<R>(&self, key: &K, f: impl FnOnce(&Volatile<V>) -> R) -> Option<R> {
+        let hash = hash_of(key);
+        let _entered = self.enter();
+        let slots = self.slots().ok()?;
+        let mut f = Some(f);
+        let mut index = hash as usize % slots.len();
+        loop {
+            let slot = &slots[index];
+            match slot.state.load(Ordering::SeqCst) {
+                EMPTY => return None,
+                OCCUPIED => {
+                    let result = slot.read(|slot| {
+                        if slot.has_key(hash, key) {
+                            f.take().map(|f| f(&slot.value))
+                        } else {
+                            None
+                        }
+                    });
+                    if let Some(Some(result)) = result {
+                        return Some(result);
+                    }
+                }
+                _ => (),
+            }
+            index = (index + 1) % slots.len();
+        }
+    }
+
+    /// A clone of the value for the key, if there is one.
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.get_with(key, |value| {
+            (*ManuallyDrop::new(value.read_volatile())).clone()
+        })
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get_with(key, |_| ()).is_some()
+    }
+
+    /// Removes the entry for the key, returning its value if there was one.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let hash = hash_of(key);
+        let _entered = self.enter();
+        let slots = self.slots().ok()?;
+        let mut index = hash as usize % slots.len();
+        loop {
+            let slot = &slots[index];
+            match slot.state.load(Ordering::SeqCst) {
+                EMPTY => return None,
+                OCCUPIED if slot.read(|slot| slot.has_key(hash, key)) == Some(true) => {
+                    if let Some((_, value)) = slot.take() {
+                        self.len.fetch_sub(1, Ordering::SeqCst);
+                        return Some(value);
+                    }
+                }
+                _ => (),
+            }
+            index = (index + 1) % slots.len();
+        }
+    }
+
+    /// Iterates over a snapshot of the entries.
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let _entered = self.enter();
+        let entries: Vec<(K, V)> = self
+            .slots()
+            .map(|slots| {
+                slots
+                    .iter()
+                    .filter_map(|slot| {
+                        slot.read(|slot| {
+                            let key = ManuallyDrop::new(slot.key.read_volatile());
+                            let value = ManuallyDrop::new(slot.value.read_volatile());
+                            ((*key).clone(), (*value).clone())
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        entries.into_iter()
+    }
+
+    // Resizes the map, or waits for someone else to.
+    fn try_resize<'a>(
+        &'a self,
+        entered: Entered<'a, K, V>,
+    ) -> Result<Entered<'a, K, V>, SharedDataError> {
+        if self.state.fetch_or(RESIZING, Ordering::SeqCst) & RESIZING != 0 {
+            drop(entered);
+            return Ok(self.enter());
+        }
+        // Wait for everyone else to finish what they're doing.
+        loop {
+            let value = self.gate.load();
+            if self.state.load(Ordering::SeqCst) & !RESIZING == 1 {
+                break;
+            }
+            self.gate.wait(value, None);
+        }
+        let result = self.rehash();
+        self.state.fetch_and(!RESIZING, Ordering::SeqCst);
+        self.gate.notify_all();
+        result.map(|()| entered)
+    }
+
+    // Only call this while resizing, so nobody else is using the map.
+    fn rehash(&self) -> Result<(), SharedDataError> {
+        let alloc = self.allocator()?;
+        let len = self.len();
+        let capacity = (2 * (len + 1)).next_power_of_two().max(MIN_CAPACITY);
+        let address = alloc_slots::<K, V>(alloc, capacity)?;
+        let old_address = self.buffer.load(Ordering::SeqCst);
+        debug!(
+            "Resizing hash map {:?} to {:?} (capacity {})",
+            old_address, address, capacity
+        );
+        let slots = slots_from_address::<K, V>(alloc, address, capacity)?;
+        for old in self.slots()? {
+            if old.state.load(Ordering::SeqCst) != OCCUPIED {
+                continue;
+            }
+            let hash = old.hash.load(Ordering::SeqCst);
+            let mut index = hash as usize % capacity;
+            while slots[index].state.load(Ordering::SeqCst) != EMPTY {
+                index = (index + 1) % capacity;
+            }
+            let slot = &slots[index];
+            slot.hash.store(hash, Ordering::SeqCst);
+            slot.key.write_volatile(old.key.read_volatile());
+            slot.value.write_volatile(old.value.read_volatile());
+            slot.state.store(OCCUPIED, Ordering::SeqCst);
+        }
+        self.buffer.store(address, Ordering::SeqCst);
+        self.capacity.store(capacity, Ordering::SeqCst);
+        self.used.store(len, Ordering::SeqCst);
+        let _ = alloc.free_bytes(old_address);
+        Ok(())
+    }
+}
+
+fn hash_of<K: Hash>(key: &K) -> u64 {
+    let mut hasher = StableHasher::default();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// The multiplier and final rotation used by FxHash.
+const FX_SEED: u64 = 0xf135_7aea_2e62_a9c5;
+const FX_ROTATE: u32 = 26;
+
+// A hasher based on FxHash, which gives the same hashes in every build,
+// so maps can be shared between processes built with different compilers.
+// Bytes are read as little-endian words, so the hashes don't depend on the architecture.
+#[derive(Default)]
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn add_to_hash(&mut self, word: u64) {
+        self.0 = self.0.wrapping_add(word).wrapping_mul(FX_SEED);
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.add_to_hash(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.add_to_hash(value);
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.add_to_hash(value as u64);
+    }
+
+    // The low bits are used to pick a slot, but they are the least mixed.
+    fn finish(&self) -> u64 {
+        self.0.rotate_left(FX_ROTATE)
+    }
+}
+
+// Resize once this many slots have been used.
+fn max_used(capacity: usize) -> usize {
+    capacity / 4 * 3
+}
+
+fn alloc_slots<K: SharedMemCast, V: SharedMemCast>(
+    alloc: &ShmemAllocator,
+    capacity: usize,
+) -> Result<SharedAddressRange, SharedDataError> {
+    let slot_size = mem::size_of::<SharedHashMapSlot<K, V>>();
+    let size = slot_size
+        .checked_mul(capacity)
+        .ok_or_else(|| SharedDataError::SizeOverflow(slot_size as u128 * capacity as u128))?;
+    let address = alloc.alloc_bytes(size)?;
+    match slots_from_address::<K, V>(alloc, address, capacity) {
+        Ok(slots) => {
+            for slot in slots {
+                slot.state.store(EMPTY, Ordering::SeqCst);
+                slot.readers.store(0, Ordering::SeqCst);
+            }
+            Ok(address)
+        }
+        Err(err) => {
+            let _ = alloc.free_bytes(address);
+            Err(err)
+        }
+    }
+}
+
+fn slots_from_address<K: SharedMemCast, V: SharedMemCast>(
+    alloc: &ShmemAllocator,
+    address: SharedAddressRange,
+    capacity: usize,
+) -> Result<&[SharedHashMapSlot<K, V>], SharedDataError> {
+    let bytes = alloc.get_bytes(address)?;
+    let volatiles = Volatile::<SharedHashMapSlot<K, V>>::slice_from_volatile_bytes(bytes, capacity)
+        .ok_or(SharedDataError::TooSmall {
+            size: bytes.len(),
+            required: mem::size_of::<SharedHashMapSlot<K, V>>().saturating_mul(capacity),
+        })?;
+    Ok(unsafe_code::slice_from_volatile(volatiles))
+}
+
+impl<K: SharedMemCast + Hash + Eq, V: SharedMemCast> Default for SharedHashMap<K, V> {
+    fn default() -> SharedHashMap<K, V> {
+        SharedHashMap::new()
+    }
+}
+
+impl<K: SharedMemCast, V: SharedMemCast> Drop for SharedHashMap<K, V> {
+    fn drop(&mut self) {
+        let address = self.buffer.load(Ordering::SeqCst);
+        debug!("Dropping hash map {:?}", address);
+        let alloc = match self.allocator() {
+            Ok(alloc) => alloc,
+            Err(_) => return,
+        };
+        // TODO: make it possible to use drop_in_place
+        if let Ok(slots) = self.slots() {
+            for slot in slots {
+                if slot.state.load(Ordering::SeqCst) == OCCUPIED {
+                    slot.key.read_volatile();
+                    slot.value.read_volatile();
+                }
+            }
+        }
+        let _ = alloc.free_bytes(address);
+    }
+}
+
+#[cfg(test)]
+use crate::SharedRc;
+#[cfg(test)]
+use crate::SharedString;
+#[cfg(test)]
+use std::panic;
+#[cfg(test)]
+use std::panic::AssertUnwindSafe;
+
+#[test]
+fn test_hash_map() {
+    let map = SharedHashMap::new();
+    assert!(map.is_empty());
+    for i in 0..100usize {
+        assert_eq!(map.insert(i, i * 2), None);
+    }
+    assert_eq!(map.len(), 100);
+    assert!(map.capacity() >= 100);
+    assert_eq!(map.get(&37), Some(74));
+    assert_eq!(map.get(&100), None);
+    assert_eq!(map.insert(37, 1), Some(74));
+    assert_eq!(map.get(&37), Some(1));
+    for i in 0..50 {
+        assert!(map.remove(&i).is_some());
+    }
+    assert_eq!(map.remove(&0), None);
+    assert!(!map.contains_key(&0));
+    assert!(map.contains_key(&50));
+    let mut entries: Vec<(usize, usize)> = map.iter().collect();
+    entries.sort();
+    assert_eq!(entries, (50..100).map(|i| (i, i * 2)).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_hash_map_values() {
+    let map = SharedRc::new(SharedHashMap::new());
+    let rc = SharedRc::new(AtomicUsize::new(37));
+    map.insert(1u32, rc.clone());
+    map.insert(2u32, SharedRc::new(AtomicUsize::new(5)));
+    assert_eq!(SharedRc::strong_count(&rc), 2);
+    map.get_with(&1, |value| value.fetch_add(1, Ordering::SeqCst));
+    assert_eq!(rc.load(Ordering::SeqCst), 38);
+    // Dropping the map drops its values
+    drop(map);
+    assert_eq!(SharedRc::strong_count(&rc), 1);
+}
+
+#[test]
+fn test_hash_map_threads() {
+    let map = SharedRc::new(SharedHashMap::new());
+    let threads: Vec<_> = (0..4usize)
+        .map(|id| {
+            let map = map.clone();
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    let key = id * 1000 + i;
+                    map.insert(key, SharedString::from(&*key.to_string()));
+                    if i % 2 == 0 {
+                        assert!(map.remove(&key).is_some());
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(map.len(), 2000);
+    for key in 0..4000 {
+        let value = map.get_with(&key, |value| value.try_to_string().unwrap());
+        assert_eq!(value, Some(key.to_string()).filter(|_| key % 2 == 1));
+    }
+}
+
+#[test]
+fn test_hash_map_panic() {
+    let map = SharedHashMap::new();
+    map.insert(1usize, 2usize);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        map.get_with(&1, |_| panic!("Panicking while reading the map"))
+    }));
+    assert!(result.is_err());
+    // The panic didn't leave the entry being read, so it can still be removed
+    assert_eq!(map.remove(&1), Some(2));
+}
+
+#[test]
+fn test_stable_hash() {
+    // Hashes must not change between builds, since maps are shared between processes
+    assert_eq!(hash_of(&37u64), 0x62d1_0a25_e772_eb0f);
+    assert_eq!(hash_of(&"hello"), 0x678b_5279_8cca_2925);
+}
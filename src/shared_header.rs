@@ -0,0 +1,59 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::SharedDataError;
+use crate::Volatile;
+use std::any;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::mem;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+/// The size of the header at the start of a vector's allocation.
+///
+/// This is a multiple of 16, so the data after the header is 16-byte aligned.
+pub(crate) const HEADER_SIZE: usize = mem::size_of::<SharedHeader>();
+
+/// A header at the start of a vector's allocation, storing its length,
+/// so that a vector can be sent to another process by address.
+///
+/// The type of an allocation is checked using the type tag in the allocator's object header,
+/// so boxes don't need a header of their own.
+#[repr(C, align(16))]
+pub(crate) struct SharedHeader {
+    length: AtomicU64,
+}
+
+impl SharedHeader {
+    /// Splits some bytes into a header and the data after it.
+    pub fn split(
+        bytes: &[Volatile<u8>],
+    ) -> Result<(&SharedHeader, &[Volatile<u8>]), SharedDataError> {
+        let too_small = SharedDataError::TooSmall {
+            size: bytes.len(),
+            required: HEADER_SIZE,
+        };
+        let header = Volatile::<SharedHeader>::from_volatile_bytes(bytes).ok_or(too_small)?;
+        Ok((header, &bytes[HEADER_SIZE..]))
+    }
+
+    pub fn length(&self) -> usize {
+        self.length.load(Ordering::SeqCst) as usize
+    }
+
+    pub fn set_length(&self, length: usize) {
+        self.length.store(length as u64, Ordering::SeqCst);
+    }
+}
+
+/// A fingerprint of a type, which is the same in every process running the same build.
+pub(crate) fn type_fingerprint<T>() -> u64 {
+    let mut hasher = DefaultHasher::new();
+    any::type_name::<T>().hash(&mut hasher);
+    mem::size_of::<T>().hash(&mut hasher);
+    mem::align_of::<T>().hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,123 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::shared_futex::futex_wait;
+use crate::shared_futex::futex_wake;
+use crate::SharedMemCast;
+use crate::Volatile;
+use log::debug;
+use std::ops::Deref;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+// Locked, and there may be processes waiting for the lock
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock that can be shared between processes.
+///
+/// On Linux, processes waiting for the lock block on a futex rather than spinning.
+/// Other platforms have no futex that works between processes, so waiting processes
+/// sleep for a millisecond at a time and check the lock again, which adds up to
+/// a millisecond to each handover, and wakes waiting processes up to a thousand
+/// times a second.
+///
+/// If a process exits while holding the lock, the lock is never released.
+///
+/// The lock only excludes processes which take it, and nothing stops another process
+/// writing to the shared memory without doing so. For that reason, holding the lock
+/// gives access to the data as a `Volatile<T>` rather than a `&mut T`.
+pub struct SharedMutex<T: SharedMemCast> {
+    state: AtomicU32,
+    data: Volatile<T>,
+}
+
+impl<T: SharedMemCast> SharedMutex<T> {
+    /// Create a new unlocked mutex.
+    pub fn new(data: T) -> SharedMutex<T> {
+        SharedMutex {
+            state: AtomicU32::new(UNLOCKED),
+            data: Volatile::new(data),
+        }
+    }
+
+    /// Acquire the lock, if it is not already locked.
+    pub fn try_lock(&self) -> Option<SharedMutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SharedMutexGuard { mutex: self })
+    }
+
+    /// Acquire the lock, waiting until it is released if it is already locked.
+    pub fn lock(&self) -> SharedMutexGuard<'_, T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+        // We don't know if there are other waiters, so we have to mark the lock as contended.
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            debug!("Waiting for lock");
+            futex_wait(&self.state, CONTENDED, None);
+        }
+        SharedMutexGuard { mutex: self }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            debug!("Wake up a waiter");
+            futex_wake(&self.state, 1);
+        }
+    }
+}
+
+/// A guard giving access to the data in a locked `SharedMutex`.
+///
+/// The lock is released when the guard is dropped.
+pub struct SharedMutexGuard<'a, T: SharedMemCast> {
+    mutex: &'a SharedMutex<T>,
+}
+
+impl<'a, T: SharedMemCast> Deref for SharedMutexGuard<'a, T> {
+    type Target = Volatile<T>;
+    fn deref(&self) -> &Volatile<T> {
+        &self.mutex.data
+    }
+}
+
+impl<'a, T: SharedMemCast> Drop for SharedMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+use crate::SharedRc;
+#[cfg(test)]
+use std::thread;
+
+#[test]
+fn test_mutex() {
+    let mutex = SharedRc::new(SharedMutex::new(0usize));
+    let guard = mutex.try_lock().unwrap();
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let mutex = mutex.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    let guard = mutex.lock();
+                    let value = guard.read_volatile();
+                    thread::yield_now();
+                    guard.write_volatile(value + 1);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(mutex.lock().read_volatile(), 4000);
+}
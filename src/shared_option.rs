@@ -2,8 +2,8 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use crate::SharedMemCast;
 use crate::Volatile;
-use shared_memory::SharedMemCast;
 use std::sync::atomic::AtomicU8;
 use std::sync::atomic::Ordering;
 
@@ -4,11 +4,13 @@
 
 use crate::SharedAddressRange;
 use crate::SharedBox;
+use crate::SharedDataError;
+use crate::SharedMemCast;
 use crate::SharedMemRef;
+use crate::ShmemAllocator;
 use crate::Volatile;
+use crate::ALLOCATOR;
 use log::debug;
-use shared_memory::SharedMemCast;
-use std::convert::From;
 use std::convert::TryFrom;
 use std::mem;
 use std::mem::ManuallyDrop;
@@ -19,49 +21,261 @@ use std::sync::atomic::Ordering;
 /// An reference counted pointer into shared memory.
 pub struct SharedRc<T: SharedMemCast>(ManuallyDrop<SharedBox<SharedRcContents<T>>>);
 
+/// A weak reference to the data in a `SharedRc`, which does not keep the data alive.
+pub struct SharedWeak<T: SharedMemCast>(ManuallyDrop<SharedBox<SharedRcContents<T>>>);
+
 // This is repr(C) to ensure that the data is placed at the beginning
 #[repr(C)]
 pub(crate) struct SharedRcContents<T: SharedMemCast> {
     data: Volatile<T>,
     ref_count: AtomicUsize,
+    // The number of weak references, plus one if there are any strong references.
+    // The memory is freed when this reaches zero.
+    weak_count: AtomicUsize,
 }
 
 impl<T: SharedMemCast> SharedRc<T> {
-    pub fn try_new(data: T) -> Option<SharedRc<T>> {
+    pub fn try_new_in(data: T, alloc: &ShmemAllocator) -> Result<SharedRc<T>, SharedDataError> {
         let ref_count = AtomicUsize::new(1);
+        let weak_count = AtomicUsize::new(1);
         let data = Volatile::new(data);
-        let contents = SharedRcContents { ref_count, data };
-        let boxed = SharedBox::try_new(contents)?;
+        let contents = SharedRcContents {
+            ref_count,
+            weak_count,
+            data,
+        };
+        let boxed = SharedBox::try_new_in(contents, alloc)?;
         debug!("Using box as Rc");
-        Some(SharedRc(ManuallyDrop::new(boxed)))
+        Ok(SharedRc(ManuallyDrop::new(boxed)))
+    }
+
+    pub fn new_in(data: T, alloc: &ShmemAllocator) -> SharedRc<T> {
+        SharedRc::try_new_in(data, alloc).expect("Failed to allocate shared Rc")
+    }
+
+    pub fn try_new(data: T) -> Result<SharedRc<T>, SharedDataError> {
+        SharedRc::try_new_in(data, *ALLOCATOR)
     }
 
     pub fn new(data: T) -> SharedRc<T> {
         SharedRc::try_new(data).expect("Failed to allocate shared Rc")
     }
 
+    pub(crate) fn allocator(this: &Self) -> Result<&'static ShmemAllocator, SharedDataError> {
+        this.0.allocator()
+    }
+
+    /// The shared address of the data.
+    ///
+    /// This does not transfer ownership: another process can use `SharedRc::try_from`
+    /// to create a new reference from it, as long as this reference is alive until then.
     pub fn address(this: &Self) -> SharedAddressRange {
         this.0.address()
     }
 
+    /// Converts the reference into a shared address, which owns its share of the count.
+    ///
+    /// The address should be converted back by exactly one call to `SharedRc::from_raw_address`,
+    /// otherwise the data is leaked.
+    pub fn into_raw_address(this: Self) -> SharedAddressRange {
+        let address = this.0.address();
+        mem::forget(this);
+        address
+    }
+
+    /// Converts a shared address created by `SharedRc::into_raw_address` back to a reference,
+    /// taking ownership of its share of the count.
+    pub fn from_raw_address(address: SharedAddressRange) -> Result<SharedRc<T>, SharedDataError> {
+        SharedRc::from_raw_address_in(address, *ALLOCATOR)
+    }
+
+    /// Converts a shared address in the given allocator created by `SharedRc::into_raw_address`
+    /// back to a reference, taking ownership of its share of the count.
+    pub fn from_raw_address_in(
+        address: SharedAddressRange,
+        alloc: &ShmemAllocator,
+    ) -> Result<SharedRc<T>, SharedDataError> {
+        let boxed = SharedBox::<SharedRcContents<T>>::try_from_address_in(address, alloc)?;
+        boxed.try_get()?;
+        Ok(SharedRc(ManuallyDrop::new(boxed)))
+    }
+
+    /// Creates a new reference from an address in the given allocator,
+    /// which is still owned by another reference.
+    pub(crate) fn try_from_address_in(
+        address: SharedAddressRange,
+        alloc: &ShmemAllocator,
+    ) -> Result<SharedRc<T>, SharedDataError> {
+        let boxed = SharedBox::<SharedRcContents<T>>::try_from_address_in(address, alloc)?;
+        boxed.try_get()?.ref_count.fetch_add(1, Ordering::SeqCst);
+        Ok(SharedRc(ManuallyDrop::new(boxed)))
+    }
+
+    /// Returns true if the two references point to the same data.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.0.address() == other.0.address()
+    }
+
+    /// Returns the data, if this is the only strong reference to it.
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        let contents = match this.0.try_get() {
+            Ok(contents) => contents,
+            Err(_) => return Err(this),
+        };
+        if contents
+            .ref_count
+            .compare_exchange(1, 0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(this);
+        }
+        let data = contents.data.read_volatile();
+        release_weak(&this.0);
+        mem::forget(this);
+        Ok(data)
+    }
+
+    /// Accesses the data, if this is the only reference to it, strong or weak.
+    ///
+    /// Another process could still write to the shared memory, or create a new reference
+    /// from an address it was sent, so this gives a `Volatile<T>` rather than a `&mut T`.
+    pub fn get_mut(this: &mut Self) -> Option<&Volatile<T>> {
+        let contents = this.0.try_get().ok()?;
+        // The strong references hold one weak reference between them, so if that is the only
+        // weak reference, nobody can upgrade a weak reference while we check the strong count.
+        if contents.weak_count.load(Ordering::Acquire) == 1
+            && contents.ref_count.load(Ordering::Acquire) == 1
+        {
+            Some(&contents.data)
+        } else {
+            None
+        }
+    }
+
     pub fn count(this: &Self) -> usize {
+        SharedRc::strong_count(this)
+    }
+
+    /// The number of strong references to the data.
+    pub fn strong_count(this: &Self) -> usize {
         this.0.ref_count.load(Ordering::SeqCst)
     }
-}
 
-impl<T: SharedMemCast> TryFrom<SharedAddressRange> for SharedRc<T> {
-    type Error = ();
-    fn try_from(address: SharedAddressRange) -> Result<SharedRc<T>, ()> {
-        Ok(SharedRc(ManuallyDrop::new(SharedBox::try_from(address)?)))
+    /// The number of weak references to the data.
+    pub fn weak_count(this: &Self) -> usize {
+        this.0.weak_count.load(Ordering::SeqCst) - 1
+    }
+
+    /// Creates a weak reference to the data.
+    pub fn downgrade(this: &Self) -> SharedWeak<T> {
+        this.0.weak_count.fetch_add(1, Ordering::SeqCst);
+        SharedWeak(ManuallyDrop::new(SharedBox::unchecked_from_address(
+            this.0.address(),
+            this.0.allocator_id(),
+        )))
     }
 }
 
-impl<T: SharedMemCast> From<SharedRc<T>> for SharedAddressRange {
-    fn from(rc: SharedRc<T>) -> SharedAddressRange {
-        let address = rc.0.address();
-        mem::forget(rc);
+impl<T: SharedMemCast> SharedWeak<T> {
+    /// Creates a strong reference to the data, returning `None` if it has been dropped.
+    pub fn upgrade(&self) -> Option<SharedRc<T>> {
+        let contents = self.0.try_get().ok()?;
+        let mut ref_count = contents.ref_count.load(Ordering::SeqCst);
+        loop {
+            if ref_count == 0 {
+                return None;
+            }
+            match contents.ref_count.compare_exchange(
+                ref_count,
+                ref_count + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => break,
+                Err(current) => ref_count = current,
+            }
+        }
+        Some(SharedRc(ManuallyDrop::new(
+            SharedBox::unchecked_from_address(self.0.address(), self.0.allocator_id()),
+        )))
+    }
+
+    /// The number of strong references to the data.
+    pub fn strong_count(&self) -> usize {
+        self.0
+            .try_get()
+            .map(|contents| contents.ref_count.load(Ordering::SeqCst))
+            .unwrap_or(0)
+    }
+
+    /// The number of weak references to the data, or zero if there are no strong references.
+    pub fn weak_count(&self) -> usize {
+        let contents = match self.0.try_get() {
+            Ok(contents) => contents,
+            Err(_) => return 0,
+        };
+        if contents.ref_count.load(Ordering::SeqCst) == 0 {
+            0
+        } else {
+            contents.weak_count.load(Ordering::SeqCst).saturating_sub(1)
+        }
+    }
+
+    /// The shared address of the data.
+    pub fn address(&self) -> SharedAddressRange {
+        self.0.address()
+    }
+
+    /// Converts the weak reference into a shared address, which owns its share of the weak count.
+    pub fn into_raw_address(self) -> SharedAddressRange {
+        let address = self.0.address();
+        mem::forget(self);
         address
     }
+
+    /// Converts a shared address created by `SharedWeak::into_raw_address` back to a weak reference.
+    pub fn from_raw_address(address: SharedAddressRange) -> Result<SharedWeak<T>, SharedDataError> {
+        SharedWeak::from_raw_address_in(address, *ALLOCATOR)
+    }
+
+    /// Converts a shared address in the given allocator created by `SharedWeak::into_raw_address`
+    /// back to a weak reference.
+    pub fn from_raw_address_in(
+        address: SharedAddressRange,
+        alloc: &ShmemAllocator,
+    ) -> Result<SharedWeak<T>, SharedDataError> {
+        let boxed = SharedBox::<SharedRcContents<T>>::try_from_address_in(address, alloc)?;
+        boxed.try_get()?;
+        Ok(SharedWeak(ManuallyDrop::new(boxed)))
+    }
+
+    /// Returns true if the two weak references point to the same data.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.0.address() == other.0.address()
+    }
+}
+
+// Drops a weak reference to the contents, freeing the memory if it was the last one.
+fn release_weak<T: SharedMemCast>(boxed: &SharedBox<SharedRcContents<T>>) {
+    let weak_count = match boxed.try_get() {
+        Ok(contents) => contents.weak_count.fetch_sub(1, Ordering::SeqCst),
+        Err(_) => return,
+    };
+    if weak_count == 1 {
+        debug!("Freeing rc");
+        // We don't drop the box, since that would drop the data again.
+        if let Ok(alloc) = boxed.allocator() {
+            let _ = alloc.free_bytes(boxed.address());
+        }
+    }
+}
+
+// Creates a new reference from an address that is still owned by another reference.
+impl<T: SharedMemCast> TryFrom<SharedAddressRange> for SharedRc<T> {
+    type Error = SharedDataError;
+    fn try_from(address: SharedAddressRange) -> Result<SharedRc<T>, SharedDataError> {
+        SharedRc::try_from_address_in(address, *ALLOCATOR)
+    }
 }
 
 impl<T: SharedMemCast + SharedMemRef> Deref for SharedRc<T> {
@@ -76,31 +290,145 @@ impl<T: SharedMemCast> Clone for SharedRc<T> {
         self.0.ref_count.fetch_add(1, Ordering::SeqCst);
         SharedRc(ManuallyDrop::new(SharedBox::unchecked_from_address(
             self.0.address(),
+            self.0.allocator_id(),
         )))
     }
 }
 
 impl<T: SharedMemCast> Drop for SharedRc<T> {
     fn drop(&mut self) {
-        let ref_count = match self.0.try_get() {
-            Some(contents) => contents.ref_count.fetch_sub(1, Ordering::SeqCst),
-            None => return,
+        let contents = match self.0.try_get() {
+            Ok(contents) => contents,
+            Err(_) => return,
         };
+        let ref_count = contents.ref_count.fetch_sub(1, Ordering::SeqCst);
         if ref_count <= 1 {
             debug!("Dropping rc");
-            mem::replace(
-                &mut self.0,
-                ManuallyDrop::new(SharedBox::unchecked_from_address(SharedAddressRange::null())),
-            );
+            // TODO: make it possible to use drop_in_place
+            contents.data.read_volatile();
+            // The strong references collectively hold a weak reference.
+            release_weak(&self.0);
         } else {
             debug!("Not dropping rc (refcount is now {})", ref_count - 1);
         }
     }
 }
 
+// Creates a new weak reference from an address that is still owned by another reference.
+impl<T: SharedMemCast> TryFrom<SharedAddressRange> for SharedWeak<T> {
+    type Error = SharedDataError;
+    fn try_from(address: SharedAddressRange) -> Result<SharedWeak<T>, SharedDataError> {
+        let boxed = SharedBox::<SharedRcContents<T>>::try_from(address)?;
+        boxed.try_get()?.weak_count.fetch_add(1, Ordering::SeqCst);
+        Ok(SharedWeak(ManuallyDrop::new(boxed)))
+    }
+}
+
+impl<T: SharedMemCast> Clone for SharedWeak<T> {
+    fn clone(&self) -> Self {
+        if let Ok(contents) = self.0.try_get() {
+            contents.weak_count.fetch_add(1, Ordering::SeqCst);
+        }
+        SharedWeak(ManuallyDrop::new(SharedBox::unchecked_from_address(
+            self.0.address(),
+            self.0.allocator_id(),
+        )))
+    }
+}
+
+impl<T: SharedMemCast> Drop for SharedWeak<T> {
+    fn drop(&mut self) {
+        release_weak(&self.0);
+    }
+}
+
 #[test]
 fn test_rc() {
     let rc: SharedRc<AtomicUsize> = SharedRc::new(AtomicUsize::new(37));
     let val = rc.load(Ordering::SeqCst);
     assert_eq!(val, 37);
 }
+
+#[cfg(test)]
+use std::convert::TryInto;
+#[cfg(test)]
+use std::sync::atomic::AtomicIsize;
+
+#[test]
+fn test_weak() {
+    let rc = SharedRc::new(AtomicUsize::new(37));
+    let weak = SharedRc::downgrade(&rc);
+    assert_eq!(SharedRc::strong_count(&rc), 1);
+    assert_eq!(SharedRc::weak_count(&rc), 1);
+    let upgraded = weak.upgrade().unwrap();
+    assert_eq!(upgraded.load(Ordering::SeqCst), 37);
+    assert_eq!(weak.strong_count(), 2);
+    drop(upgraded);
+    // Weak references can be passed by address
+    let address = weak.clone().into_raw_address();
+    let weak2: SharedWeak<AtomicUsize> = SharedWeak::from_raw_address(address).unwrap();
+    assert_eq!(weak2.weak_count(), 2);
+    let weak3: SharedWeak<AtomicUsize> = weak.address().try_into().unwrap();
+    assert!(weak3.ptr_eq(&weak));
+    assert_eq!(weak3.weak_count(), 3);
+    drop(rc);
+    assert!(weak.upgrade().is_none());
+    assert!(weak2.upgrade().is_none());
+    assert_eq!(weak.strong_count(), 0);
+    assert_eq!(weak.weak_count(), 0);
+}
+
+#[test]
+fn test_rc_address() {
+    let rc = SharedRc::new(AtomicUsize::new(37));
+    // Borrowing the address increments the count
+    let rc2: SharedRc<AtomicUsize> = SharedRc::address(&rc).try_into().unwrap();
+    assert!(SharedRc::ptr_eq(&rc, &rc2));
+    assert_eq!(SharedRc::strong_count(&rc), 2);
+    // Passing ownership of the address keeps the count balanced
+    let address = SharedRc::into_raw_address(rc2);
+    assert_eq!(SharedRc::strong_count(&rc), 2);
+    let rc2: SharedRc<AtomicUsize> = SharedRc::from_raw_address(address).unwrap();
+    assert_eq!(SharedRc::strong_count(&rc), 2);
+    drop(rc2);
+    assert_eq!(SharedRc::strong_count(&rc), 1);
+    // Addresses of other types are refused, even if they are the same size
+    assert!(matches!(
+        SharedRc::<AtomicIsize>::try_from(SharedRc::address(&rc)),
+        Err(SharedDataError::WrongType(_))
+    ));
+    assert!(SharedWeak::<AtomicIsize>::try_from(SharedRc::address(&rc)).is_err());
+    assert_eq!(SharedRc::strong_count(&rc), 1);
+    let boxed = SharedBox::new(AtomicUsize::new(37));
+    assert!(SharedRc::<AtomicUsize>::try_from(boxed.address()).is_err());
+}
+
+#[test]
+fn test_rc_unique() {
+    let rc = SharedRc::new(AtomicUsize::new(37));
+    rc.fetch_add(1, Ordering::SeqCst);
+    let rc2 = rc.clone();
+    let rc = SharedRc::try_unwrap(rc).err().unwrap();
+    drop(rc2);
+    let weak = SharedRc::downgrade(&rc);
+    let value = SharedRc::try_unwrap(rc).ok().unwrap();
+    assert_eq!(value.into_inner(), 38);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_rc_get_mut() {
+    let mut rc = SharedRc::new(AtomicUsize::new(37));
+    SharedRc::get_mut(&mut rc)
+        .unwrap()
+        .write_volatile(AtomicUsize::new(38));
+    assert_eq!(rc.load(Ordering::SeqCst), 38);
+    // Other strong or weak references stop the data being accessed
+    let rc2 = rc.clone();
+    assert!(SharedRc::get_mut(&mut rc).is_none());
+    drop(rc2);
+    let weak = SharedRc::downgrade(&rc);
+    assert!(SharedRc::get_mut(&mut rc).is_none());
+    drop(weak);
+    assert!(SharedRc::get_mut(&mut rc).is_some());
+}
@@ -0,0 +1,302 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::unsafe_code;
+use crate::AtomicSharedAddressRange;
+use crate::Reading;
+use crate::SharedAddressRange;
+use crate::SharedDataError;
+use crate::ShmemName;
+use crate::Volatile;
+use std::process;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+// The kinds of state a root can be in. While a root is reserved, the state also
+// holds the id of the process that reserved it, so other processes can take the root
+// back if it dies. The rest of the state is a count of changes, so a process waiting
+// for a root can tell whether it has moved on.
+const EMPTY: u64 = 0;
+const RESERVED: u64 = 1;
+const PUBLISHED: u64 = 2;
+const REMOVING: u64 = 3;
+const KIND_MASK: u64 = 0xff;
+const PID_SHIFT: u32 = 8;
+const COUNT_SHIFT: u32 = 40;
+
+fn kind(state: u64) -> u64 {
+    state & KIND_MASK
+}
+
+fn next_state(state: u64, kind: u64) -> u64 {
+    let count = (state >> COUNT_SHIFT).wrapping_add(1);
+    (count << COUNT_SHIFT) | kind
+}
+
+fn reserving(state: u64, pid: u32) -> u64 {
+    next_state(state, RESERVED) | ((pid as u64) << PID_SHIFT)
+}
+
+fn reserver(state: u64) -> u32 {
+    (state >> PID_SHIFT) as u32
+}
+
+/// An object published by name in the allocator's metadata.
+pub(crate) struct SharedRoot {
+    state: AtomicU64,
+    // The number of processes looking the root up, which stop it from being unpublished.
+    readers: AtomicUsize,
+    name: Volatile<ShmemName>,
+    // Used to check that the object is looked up at the type it was published at.
+    fingerprint: AtomicU64,
+    address: AtomicSharedAddressRange,
+}
+
+impl SharedRoot {
+    pub fn new() -> SharedRoot {
+        SharedRoot {
+            state: AtomicU64::new(EMPTY),
+            readers: AtomicUsize::new(0),
+            name: Volatile::new(ShmemName::default()),
+            fingerprint: AtomicU64::new(0),
+            address: AtomicSharedAddressRange::default(),
+        }
+    }
+
+    // Waits for a reserved root to be published or released. If the process that
+    // reserved it has died, the root is made empty again. Other processes can only
+    // tell that on Linux, so elsewhere a root abandoned this way stays reserved.
+    fn wait_for_reservation(&self, state: u64) {
+        while self.state.load(Ordering::SeqCst) == state {
+            if !unsafe_code::process_is_alive(reserver(state)) {
+                let _ = self.state.compare_exchange(
+                    state,
+                    next_state(state, EMPTY),
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                );
+                return;
+            }
+            thread::yield_now();
+        }
+    }
+
+    // Only call this while the root is published, and check the state hasn't changed afterwards.
+    fn has_name(&self, name: ShmemName) -> bool {
+        self.name.read_volatile() == name
+    }
+}
+
+/// Publishes an address in an empty root, checking that the name is not taken.
+pub(crate) fn publish_root(
+    roots: &[SharedRoot],
+    name: &str,
+    fingerprint: u64,
+    address: SharedAddressRange,
+) -> Result<(), SharedDataError> {
+    let shmem_name = ShmemName::from_str(name)
+        .ok_or_else(|| SharedDataError::NameTooLong(String::from(name)))?;
+    // If another process thought we had died, it may have taken the root, so start again.
+    while !try_publish_root(roots, name, shmem_name, fingerprint, address)? {}
+    Ok(())
+}
+
+fn try_publish_root(
+    roots: &[SharedRoot],
+    name: &str,
+    shmem_name: ShmemName,
+    fingerprint: u64,
+    address: SharedAddressRange,
+) -> Result<bool, SharedDataError> {
+    // Reserve the first empty root, then check the rest of the roots for the name.
+    // Processes only wait for roots reserved after theirs, so they can't wait for each other.
+    let mut reserved = None;
+    for root in roots {
+        loop {
+            let state = root.state.load(Ordering::SeqCst);
+            match kind(state) {
+                EMPTY if reserved.is_none() => {
+                    let next = reserving(state, process::id());
+                    if root
+                        .state
+                        .compare_exchange(state, next, Ordering::SeqCst, Ordering::SeqCst)
+                        .is_ok()
+                    {
+                        root.name.write_volatile(shmem_name);
+                        root.fingerprint.store(fingerprint, Ordering::SeqCst);
+                        root.address.store(address, Ordering::SeqCst);
+                        reserved = Some((root, next));
+                        break;
+                    }
+                }
+                // Wait for the name to be written, in case it's this name.
+                RESERVED => root.wait_for_reservation(state),
+                PUBLISHED if root.has_name(shmem_name) => {
+                    if root.state.load(Ordering::SeqCst) != state {
+                        continue;
+                    }
+                    if let Some((root, state)) = reserved {
+                        let _ = root.state.compare_exchange(
+                            state,
+                            next_state(state, EMPTY),
+                            Ordering::SeqCst,
+                            Ordering::SeqCst,
+                        );
+                    }
+                    return Err(SharedDataError::AlreadyPublished(String::from(name)));
+                }
+                _ => break,
+            }
+        }
+    }
+    let (root, state) = reserved.ok_or(SharedDataError::OutOfRoots)?;
+    Ok(root
+        .state
+        .compare_exchange(
+            state,
+            next_state(state, PUBLISHED),
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        )
+        .is_ok())
+}
+
+/// Looks up the address published with a name, checking its type fingerprint.
+///
+/// The root can't be unpublished while `f` is running, so it can take
+/// a new reference to the object.
+pub(crate) fn lookup_root<R>(
+    roots: &[SharedRoot],
+    name: &str,
+    fingerprint: u64,
+    f: impl FnOnce(SharedAddressRange) -> Result<R, SharedDataError>,
+) -> Result<R, SharedDataError> {
+    let not_published = || SharedDataError::NotPublished(String::from(name));
+    let shmem_name = ShmemName::from_str(name).ok_or_else(not_published)?;
+    for root in roots {
+        let _reading = Reading::new(&root.readers);
+        if kind(root.state.load(Ordering::SeqCst)) == PUBLISHED && root.has_name(shmem_name) {
+            if root.fingerprint.load(Ordering::SeqCst) != fingerprint {
+                return Err(SharedDataError::TypeMismatch(String::from(name)));
+            }
+            return f(root.address.load(Ordering::SeqCst));
+        }
+    }
+    Err(not_published())
+}
+
+/// Unpublishes the root with a name, checking its type fingerprint,
+/// and returns its address, which owns the root's reference to the object.
+pub(crate) fn unpublish_root(
+    roots: &[SharedRoot],
+    name: &str,
+    fingerprint: u64,
+) -> Result<SharedAddressRange, SharedDataError> {
+    let not_published = || SharedDataError::NotPublished(String::from(name));
+    let shmem_name = ShmemName::from_str(name).ok_or_else(not_published)?;
+    for root in roots {
+        loop {
+            let state = root.state.load(Ordering::SeqCst);
+            if kind(state) != PUBLISHED || !root.has_name(shmem_name) {
+                break;
+            }
+            if root.fingerprint.load(Ordering::SeqCst) != fingerprint {
+                if root.state.load(Ordering::SeqCst) != state {
+                    continue;
+                }
+                return Err(SharedDataError::TypeMismatch(String::from(name)));
+            }
+            let removing = next_state(state, REMOVING);
+            if root
+                .state
+                .compare_exchange(state, removing, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                // Wait for any lookups to finish taking their references.
+                while root.readers.load(Ordering::SeqCst) != 0 {
+                    thread::yield_now();
+                }
+                let address = root.address.load(Ordering::SeqCst);
+                root.state
+                    .store(next_state(removing, EMPTY), Ordering::SeqCst);
+                return Ok(address);
+            }
+        }
+    }
+    Err(not_published())
+}
+
+#[cfg(test)]
+use crate::shared_header::type_fingerprint;
+
+#[test]
+fn test_roots() {
+    let roots = [SharedRoot::new(), SharedRoot::new()];
+    let address = SharedAddressRange::from(37);
+    let fingerprint = type_fingerprint::<u32>();
+    assert_ne!(fingerprint, type_fingerprint::<i32>());
+    publish_root(&roots, "a", fingerprint, address).unwrap();
+    assert!(matches!(
+        publish_root(&roots, "a", fingerprint, address),
+        Err(SharedDataError::AlreadyPublished(_))
+    ));
+    publish_root(&roots, "b", fingerprint, address).unwrap();
+    assert!(matches!(
+        publish_root(&roots, "c", fingerprint, address),
+        Err(SharedDataError::OutOfRoots)
+    ));
+    assert_eq!(lookup_root(&roots, "b", fingerprint, Ok).unwrap(), address);
+    assert!(matches!(
+        lookup_root(&roots, "b", type_fingerprint::<i32>(), Ok),
+        Err(SharedDataError::TypeMismatch(_))
+    ));
+    assert!(matches!(
+        lookup_root(&roots, "c", fingerprint, Ok),
+        Err(SharedDataError::NotPublished(_))
+    ));
+}
+
+#[test]
+fn test_unpublish_root() {
+    let roots = [SharedRoot::new(), SharedRoot::new()];
+    let address = SharedAddressRange::from(37);
+    let fingerprint = type_fingerprint::<u32>();
+    publish_root(&roots, "a", fingerprint, address).unwrap();
+    publish_root(&roots, "b", fingerprint, address).unwrap();
+    assert!(matches!(
+        unpublish_root(&roots, "a", type_fingerprint::<i32>()),
+        Err(SharedDataError::TypeMismatch(_))
+    ));
+    assert_eq!(unpublish_root(&roots, "a", fingerprint).unwrap(), address);
+    assert!(matches!(
+        lookup_root(&roots, "a", fingerprint, Ok),
+        Err(SharedDataError::NotPublished(_))
+    ));
+    // The root can be reused, and the name is still checked against later roots
+    assert!(matches!(
+        publish_root(&roots, "b", fingerprint, address),
+        Err(SharedDataError::AlreadyPublished(_))
+    ));
+    publish_root(&roots, "c", fingerprint, address).unwrap();
+    assert_eq!(lookup_root(&roots, "c", fingerprint, Ok).unwrap(), address);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_abandoned_root() {
+    let roots = [SharedRoot::new(), SharedRoot::new()];
+    let address = SharedAddressRange::from(37);
+    let fingerprint = type_fingerprint::<u32>();
+    // A process that dies after reserving a root doesn't block anyone else
+    let mut child = process::Command::new("true").spawn().unwrap();
+    child.wait().unwrap();
+    let state = reserving(roots[0].state.load(Ordering::SeqCst), child.id());
+    roots[0].state.store(state, Ordering::SeqCst);
+    publish_root(&roots, "a", fingerprint, address).unwrap();
+    publish_root(&roots, "b", fingerprint, address).unwrap();
+    assert_eq!(lookup_root(&roots, "a", fingerprint, Ok).unwrap(), address);
+    assert_eq!(lookup_root(&roots, "b", fingerprint, Ok).unwrap(), address);
+}
@@ -0,0 +1,172 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::SharedAddressRange;
+use crate::SharedDataError;
+use crate::SharedVec;
+use crate::ShmemAllocator;
+use crate::ALLOCATOR;
+use std::fmt;
+
+/// A UTF-8 string in shared memory.
+///
+/// Another process may write to the string at any time, so its contents
+/// are copied out and validated every time they are read.
+pub struct SharedString(SharedVec<u8>);
+
+impl SharedString {
+    pub fn try_from_str_in(
+        string: &str,
+        alloc: &ShmemAllocator,
+    ) -> Result<SharedString, SharedDataError> {
+        Ok(SharedString(SharedVec::try_from_iter_in(
+            string.bytes(),
+            alloc,
+        )?))
+    }
+
+    pub fn from_str_in(string: &str, alloc: &ShmemAllocator) -> SharedString {
+        SharedString::try_from_str_in(string, alloc).expect("Failed to allocate shared string")
+    }
+
+    pub fn try_from_str(string: &str) -> Result<SharedString, SharedDataError> {
+        SharedString::try_from_str_in(string, *ALLOCATOR)
+    }
+
+    pub fn try_new_in(alloc: &ShmemAllocator) -> Result<SharedString, SharedDataError> {
+        SharedString::try_from_str_in("", alloc)
+    }
+
+    pub fn new_in(alloc: &ShmemAllocator) -> SharedString {
+        SharedString::try_new_in(alloc).expect("Failed to allocate shared string")
+    }
+
+    pub fn try_new() -> Result<SharedString, SharedDataError> {
+        SharedString::try_new_in(*ALLOCATOR)
+    }
+
+    pub fn new() -> SharedString {
+        SharedString::try_new().expect("Failed to allocate shared string")
+    }
+
+    pub fn address(&self) -> SharedAddressRange {
+        self.0.address()
+    }
+
+    /// The length of the string in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Appends a string, returning an error if reallocation failed.
+    pub fn try_push_str(&mut self, string: &str) -> Result<(), SharedDataError> {
+        self.0.try_reserve(string.len())?;
+        for byte in string.bytes() {
+            self.0
+                .try_push(byte)
+                .map_err(|_| SharedDataError::TooLarge(string.len()))?;
+        }
+        Ok(())
+    }
+
+    /// Appends a string, panicing if reallocation failed.
+    pub fn push_str(&mut self, string: &str) {
+        self.try_push_str(string)
+            .expect("Failed to push onto shared string")
+    }
+
+    /// Appends a character, panicing if reallocation failed.
+    pub fn push(&mut self, ch: char) {
+        self.push_str(ch.encode_utf8(&mut [0; 4]))
+    }
+
+    /// A snapshot of the bytes of the string, which may not be valid UTF-8.
+    pub fn try_to_bytes(&self) -> Result<Vec<u8>, SharedDataError> {
+        Ok(self
+            .0
+            .try_get()?
+            .iter()
+            .map(|volatile| volatile.read_volatile())
+            .collect())
+    }
+
+    /// A snapshot of the string, returning an error if it is not valid UTF-8.
+    pub fn try_to_string(&self) -> Result<String, SharedDataError> {
+        String::from_utf8(self.try_to_bytes()?)
+            .map_err(|err| SharedDataError::InvalidUtf8(err.utf8_error()))
+    }
+}
+
+impl Default for SharedString {
+    fn default() -> SharedString {
+        SharedString::new()
+    }
+}
+
+impl<'a> From<&'a str> for SharedString {
+    fn from(string: &'a str) -> SharedString {
+        SharedString::try_from_str(string).expect("Failed to allocate shared string")
+    }
+}
+
+impl<'a> Extend<&'a str> for SharedString {
+    fn extend<I: IntoIterator<Item = &'a str>>(&mut self, iter: I) {
+        for string in iter {
+            self.push_str(string);
+        }
+    }
+}
+
+impl Extend<char> for SharedString {
+    fn extend<I: IntoIterator<Item = char>>(&mut self, iter: I) {
+        for ch in iter {
+            self.push(ch);
+        }
+    }
+}
+
+// Displaying is lossy, since `to_string` and `format!` panic if it fails.
+// Invalid UTF-8 is replaced by U+FFFD, and a string that can't be read is empty.
+// Use `try_to_string` to check the string is valid.
+impl fmt::Display for SharedString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = self.try_to_bytes().unwrap_or_default();
+        f.write_str(&String::from_utf8_lossy(&bytes))
+    }
+}
+
+impl fmt::Debug for SharedString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_to_string() {
+            Ok(string) => fmt::Debug::fmt(&string, f),
+            Err(err) => write!(f, "<{}>", err),
+        }
+    }
+}
+
+#[test]
+fn test_string() {
+    let mut string = SharedString::from("hello");
+    assert_eq!(string.to_string(), "hello");
+    string.push(',');
+    string.push_str(" world");
+    string.extend(vec!["!", " ☃"]);
+    assert_eq!(string.len(), 17);
+    assert_eq!(string.try_to_string().unwrap(), "hello, world! ☃");
+    assert_eq!(format!("{:?}", string), "\"hello, world! ☃\"");
+}
+
+#[test]
+fn test_invalid_string() {
+    let string = SharedString::from("☃");
+    // Another process writes invalid UTF-8
+    string.0.get()[0].write_volatile(b'a');
+    assert_eq!(string.try_to_bytes().unwrap(), [b'a', 0x98, 0x83]);
+    assert!(string.try_to_string().is_err());
+    assert_eq!(string.to_string(), "a\u{FFFD}\u{FFFD}");
+}
@@ -2,14 +2,21 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use crate::shared_header::type_fingerprint;
+use crate::shared_header::SharedHeader;
+use crate::shared_header::HEADER_SIZE;
 use crate::unsafe_code;
+use crate::AllocatorId;
 use crate::SharedAddressRange;
+use crate::SharedDataError;
+use crate::SharedMemCast;
 use crate::SharedMemRef;
 use crate::ShmemAllocator;
 use crate::Volatile;
 use crate::ALLOCATOR;
 use log::debug;
-use shared_memory::SharedMemCast;
+use std::convert::TryFrom;
+use std::iter;
 use std::marker::PhantomData;
 use std::mem;
 use std::ops::Deref;
@@ -20,12 +27,16 @@ use std::sync::atomic::Ordering;
 /// An owned pointer into an array of shared memory.
 pub struct SharedVec<T: SharedMemCast> {
     address: SharedAddressRange,
+    allocator_id: AllocatorId,
     length: AtomicUsize,
     marker: PhantomData<T>,
 }
 
 impl<T: SharedMemCast> SharedVec<T> {
-    pub(crate) fn from_iter_in<C>(collection: C, alloc: &ShmemAllocator) -> Option<SharedVec<T>>
+    pub fn try_from_iter_in<C>(
+        collection: C,
+        alloc: &ShmemAllocator,
+    ) -> Result<SharedVec<T>, SharedDataError>
     where
         C: IntoIterator<Item = T>,
         C::IntoIter: ExactSizeIterator,
@@ -34,35 +45,39 @@ impl<T: SharedMemCast> SharedVec<T> {
         let length = iter.len();
         debug!("Allocating vector of length {}", length);
         let size = mem::size_of::<T>() * length;
-        let address = alloc.alloc_bytes(size)?;
-        let bytes = alloc.get_bytes(address)?;
-        let slice = Volatile::<T>::slice_from_volatile_bytes(bytes, length)?;
+        let address = alloc.alloc_tagged(HEADER_SIZE + size, type_fingerprint::<SharedVec<T>>())?;
+        let (header, bytes) = SharedHeader::split(alloc.get_bytes(address)?)?;
+        let slice = slice_from_bytes::<T>(bytes, length)?;
         debug!("Initializing vector");
+        header.set_length(length);
         for (item, volatile) in iter.zip(slice) {
             volatile.write_volatile(item);
         }
+        let allocator_id = alloc.id();
         let length = AtomicUsize::new(length);
         let marker = PhantomData;
-        Some(SharedVec {
+        Ok(SharedVec {
             address,
+            allocator_id,
             length,
             marker,
         })
     }
 
-    pub(crate) fn as_ptr_in(&self, alloc: &ShmemAllocator) -> *mut T {
-        alloc
-            .get_bytes(self.address)
-            .map(|bytes| bytes.as_ptr() as *mut T)
-            .unwrap_or(ptr::null_mut())
+    pub fn from_iter_in<C>(collection: C, alloc: &ShmemAllocator) -> SharedVec<T>
+    where
+        C: IntoIterator<Item = T>,
+        C::IntoIter: ExactSizeIterator,
+    {
+        SharedVec::try_from_iter_in(collection, alloc).expect("Failed to allocate shared vec")
     }
 
-    pub fn try_from_iter<C>(collection: C) -> Option<SharedVec<T>>
+    pub fn try_from_iter<C>(collection: C) -> Result<SharedVec<T>, SharedDataError>
     where
         C: IntoIterator<Item = T>,
         C::IntoIter: ExactSizeIterator,
     {
-        SharedVec::from_iter_in(collection, &ALLOCATOR)
+        SharedVec::try_from_iter_in(collection, *ALLOCATOR)
     }
 
     pub fn from_iter<C>(collection: C) -> SharedVec<T>
@@ -73,18 +88,56 @@ impl<T: SharedMemCast> SharedVec<T> {
         SharedVec::try_from_iter(collection).expect("Failed to allocate shared vec")
     }
 
+    pub fn try_new_in(alloc: &ShmemAllocator) -> Result<SharedVec<T>, SharedDataError> {
+        SharedVec::try_from_iter_in(iter::empty(), alloc)
+    }
+
+    pub fn new_in(alloc: &ShmemAllocator) -> SharedVec<T> {
+        SharedVec::try_new_in(alloc).expect("Failed to allocate shared vec")
+    }
+
+    pub fn try_new() -> Result<SharedVec<T>, SharedDataError> {
+        SharedVec::try_new_in(*ALLOCATOR)
+    }
+
+    pub fn new() -> SharedVec<T> {
+        SharedVec::try_new().expect("Failed to allocate shared vec")
+    }
+
     pub fn as_ptr(&self) -> *mut T {
-        self.as_ptr_in(&ALLOCATOR)
+        self.allocator()
+            .and_then(|alloc| alloc.get_bytes(self.address))
+            .and_then(SharedHeader::split)
+            .map(|(_, bytes)| bytes.as_ptr() as *mut T)
+            .unwrap_or(ptr::null_mut())
+    }
+
+    pub(crate) fn allocator(&self) -> Result<&'static ShmemAllocator, SharedDataError> {
+        ShmemAllocator::from_id(self.allocator_id)
     }
 
-    pub(crate) fn get_in<'a>(&'a self, alloc: &'a ShmemAllocator) -> Option<&'a [Volatile<T>]> {
-        let bytes = alloc.get_bytes(self.address)?;
+    pub fn try_get(&self) -> Result<&[Volatile<T>], SharedDataError> {
+        let (_, bytes) = self.try_get_header()?;
         let length = self.length.load(Ordering::SeqCst);
-        Volatile::slice_from_volatile_bytes(bytes, length)
+        slice_from_bytes(bytes, length)
+    }
+
+    // The whole buffer, including the spare capacity.
+    fn try_get_buffer(&self) -> Result<&[Volatile<T>], SharedDataError> {
+        let (_, bytes) = self.try_get_header()?;
+        slice_from_bytes(bytes, self.capacity())
     }
 
-    pub fn try_get(&self) -> Option<&[Volatile<T>]> {
-        self.get_in(&ALLOCATOR)
+    fn try_get_header(&self) -> Result<(&SharedHeader, &[Volatile<u8>]), SharedDataError> {
+        SharedHeader::split(self.allocator()?.get_bytes(self.address)?)
+    }
+
+    // Sets the length, keeping the header in sync, so the vector can be sent by address.
+    fn set_len(&self, length: usize) {
+        self.length.store(length, Ordering::SeqCst);
+        if let Ok((header, _)) = self.try_get_header() {
+            header.set_length(length);
+        }
     }
 
     pub fn get(&self) -> &[Volatile<T>] {
@@ -98,12 +151,221 @@ impl<T: SharedMemCast> SharedVec<T> {
     pub fn len(&self) -> usize {
         self.length.load(Ordering::Relaxed)
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of elements the vector can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        let size = self.try_get_header().map_or(0, |(_, bytes)| bytes.len());
+        size.checked_div(mem::size_of::<T>()).unwrap_or(usize::MAX)
+    }
+
+    /// Reserves capacity for at least `additional` more elements,
+    /// returning an error if reallocation failed.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), SharedDataError> {
+        let length = self.len();
+        let capacity = self.capacity();
+        let required = length
+            .checked_add(additional)
+            .ok_or_else(|| overflow::<T>(length as u128 + additional as u128))?;
+        if required <= capacity {
+            return Ok(());
+        }
+        let alloc = self.allocator()?;
+        let old_slice = self.try_get()?;
+        let new_capacity = usize::max(required, capacity.saturating_mul(2));
+        let new_size = mem::size_of::<T>()
+            .checked_mul(new_capacity)
+            .and_then(|size| size.checked_add(HEADER_SIZE))
+            .ok_or_else(|| overflow::<T>(new_capacity as u128))?;
+        let new_address = alloc.alloc_tagged(new_size, type_fingerprint::<SharedVec<T>>())?;
+        let new_slice = alloc
+            .get_bytes(new_address)
+            .and_then(SharedHeader::split)
+            .and_then(|(header, bytes)| {
+                header.set_length(length);
+                slice_from_bytes::<T>(bytes, length)
+            });
+        let new_slice = match new_slice {
+            Ok(new_slice) => new_slice,
+            Err(err) => {
+                let _ = alloc.free_bytes(new_address);
+                return Err(err);
+            }
+        };
+        debug!(
+            "Reallocating vector {:?} to {:?}",
+            self.address, new_address
+        );
+        for (old, new) in old_slice.iter().zip(new_slice) {
+            new.write_volatile(old.read_volatile());
+        }
+        let _ = alloc.free_bytes(self.address);
+        self.address = new_address;
+        Ok(())
+    }
+
+    /// Reserves capacity for at least `additional` more elements,
+    /// panicing if reallocation failed.
+    pub fn reserve(&mut self, additional: usize) {
+        self.try_reserve(additional)
+            .expect("Failed to reserve shared vec")
+    }
+
+    /// Appends an element, returning it back if reallocation failed.
+    pub fn try_push(&mut self, value: T) -> Result<(), T> {
+        if self.try_reserve(1).is_err() {
+            return Err(value);
+        }
+        let length = self.len();
+        match self
+            .try_get_buffer()
+            .ok()
+            .and_then(|buffer| buffer.get(length))
+        {
+            Some(volatile) => volatile.write_volatile(value),
+            None => return Err(value),
+        }
+        self.set_len(length + 1);
+        Ok(())
+    }
+
+    /// Appends an element, panicing if reallocation failed.
+    pub fn push(&mut self, value: T) {
+        if self.try_push(value).is_err() {
+            panic!("Failed to push onto shared vec");
+        }
+    }
+
+    /// Removes the last element, returning `None` if the vector is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        let length = self.len().checked_sub(1)?;
+        let result = self.try_get().ok()?.get(length)?.read_volatile();
+        self.set_len(length);
+        Some(result)
+    }
+
+    /// Shortens the vector, dropping any elements past `length`.
+    pub fn truncate(&mut self, length: usize) {
+        if length >= self.len() {
+            return;
+        }
+        // TODO: make it possible to use drop_in_place
+        if let Some(volatiles) = self
+            .try_get()
+            .ok()
+            .and_then(|volatiles| volatiles.get(length..))
+        {
+            for volatile in volatiles {
+                volatile.read_volatile();
+            }
+        }
+        self.set_len(length);
+    }
+
+    /// Inserts an element at position `index`, shifting all the elements after it.
+    ///
+    /// Panics if `index` is greater than the length, or reallocation failed.
+    pub fn insert(&mut self, index: usize, element: T) {
+        let length = self.len();
+        assert!(
+            index <= length,
+            "Insertion index {} out of bounds {}",
+            index,
+            length
+        );
+        self.reserve(1);
+        let buffer = self.try_get_buffer().expect("Failed to deref shared vec");
+        for pair in buffer[index..=length].windows(2).rev() {
+            pair[1].write_volatile(pair[0].read_volatile());
+        }
+        buffer[index].write_volatile(element);
+        self.set_len(length + 1);
+    }
+
+    /// Removes the element at position `index`, shifting all the elements after it.
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn remove(&mut self, index: usize) -> T {
+        let length = self.len();
+        assert!(
+            index < length,
+            "Removal index {} out of bounds {}",
+            index,
+            length
+        );
+        let slice = self.get();
+        let result = slice[index].read_volatile();
+        for pair in slice[index..].windows(2) {
+            pair[0].write_volatile(pair[1].read_volatile());
+        }
+        self.set_len(length - 1);
+        result
+    }
+}
+
+fn slice_from_bytes<T: SharedMemCast>(
+    bytes: &[Volatile<u8>],
+    length: usize,
+) -> Result<&[Volatile<T>], SharedDataError> {
+    Volatile::slice_from_volatile_bytes(bytes, length).ok_or(SharedDataError::TooSmall {
+        size: bytes.len(),
+        required: mem::size_of::<T>().saturating_mul(length),
+    })
+}
+
+// The error for a vector whose size in bytes doesn't fit in a `usize`.
+fn overflow<T>(capacity: u128) -> SharedDataError {
+    let size = capacity * mem::size_of::<T>() as u128 + HEADER_SIZE as u128;
+    SharedDataError::SizeOverflow(size)
+}
+
+impl<T: SharedMemCast> TryFrom<SharedAddressRange> for SharedVec<T> {
+    type Error = SharedDataError;
+    fn try_from(address: SharedAddressRange) -> Result<SharedVec<T>, SharedDataError> {
+        let bytes = ALLOCATOR.get_tagged_bytes(address, type_fingerprint::<SharedVec<T>>())?;
+        let (header, bytes) = SharedHeader::split(bytes)?;
+        let length = header.length();
+        slice_from_bytes::<T>(bytes, length)?;
+        Ok(SharedVec {
+            address,
+            allocator_id: ALLOCATOR.id(),
+            length: AtomicUsize::new(length),
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<T: SharedMemCast> From<SharedVec<T>> for SharedAddressRange {
+    fn from(vec: SharedVec<T>) -> SharedAddressRange {
+        let address = vec.address;
+        mem::forget(vec);
+        address
+    }
+}
+
+impl<T: SharedMemCast> Default for SharedVec<T> {
+    fn default() -> SharedVec<T> {
+        SharedVec::new()
+    }
+}
+
+impl<T: SharedMemCast> Extend<T> for SharedVec<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for item in iter {
+            self.push(item);
+        }
+    }
 }
 
 impl<T: SharedMemCast + SharedMemRef> Deref for SharedVec<T> {
     type Target = [T];
     fn deref(&self) -> &[T] {
-        if let Some(volatile) = self.try_get() {
+        if let Ok(volatile) = self.try_get() {
             unsafe_code::slice_from_volatile(volatile)
         } else {
             unsafe_code::slice_empty()
@@ -115,12 +377,14 @@ impl<T: SharedMemCast> Drop for SharedVec<T> {
     fn drop(&mut self) {
         debug!("Dropping vector {:?}", self.address);
         // TODO: make it possible to use drop_in_place
-        if let Some(volatiles) = self.try_get() {
+        if let Ok(volatiles) = self.try_get() {
             for volatile in volatiles {
                 volatile.read_volatile();
             }
         }
-        ALLOCATOR.free_bytes(self.address);
+        if let Ok(alloc) = self.allocator() {
+            let _ = alloc.free_bytes(self.address);
+        }
     }
 }
 
@@ -136,3 +400,102 @@ fn test_vector() {
     }
     assert_eq!(last, 37);
 }
+
+#[cfg(test)]
+use std::sync::atomic::AtomicBool;
+#[cfg(test)]
+use std::sync::atomic::AtomicI64;
+
+#[test]
+fn test_std_vectors() {
+    let vec = SharedVec::from_iter(
+        (0..4i32).map(|i| [AtomicI64::new(i.into()), AtomicI64::new((-i).into())]),
+    );
+    assert_eq!(vec[3][1].load(Ordering::SeqCst), -3);
+    let vec =
+        SharedVec::from_iter((0..4).map(|i| (AtomicBool::new(i % 2 == 0), AtomicUsize::new(i))));
+    assert!(vec[2].0.load(Ordering::SeqCst));
+    assert_eq!(vec[3].1.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn test_push_pop() {
+    let mut vec = SharedVec::new();
+    for i in 0..37 {
+        vec.push(AtomicUsize::new(i));
+    }
+    assert_eq!(vec.len(), 37);
+    assert!(vec.capacity() >= 37);
+    for (i, atomic) in vec.iter().enumerate() {
+        assert_eq!(atomic.load(Ordering::SeqCst), i);
+    }
+    vec.truncate(5);
+    assert_eq!(vec.len(), 5);
+    assert_eq!(vec.pop().unwrap().load(Ordering::SeqCst), 4);
+    vec.extend((10..15).map(AtomicUsize::new));
+    let vals: Vec<usize> = vec
+        .iter()
+        .map(|atomic| atomic.load(Ordering::SeqCst))
+        .collect();
+    assert_eq!(vals, [0, 1, 2, 3, 10, 11, 12, 13, 14]);
+    // Reserving too much reports the size that overflowed
+    assert!(matches!(
+        vec.try_reserve(usize::MAX),
+        Err(SharedDataError::SizeOverflow(size)) if size == (usize::MAX as u128 + 9) * 8 + 16
+    ));
+}
+
+#[test]
+fn test_insert_remove() {
+    let mut vec = SharedVec::from_iter((0..4).map(AtomicUsize::new));
+    vec.insert(0, AtomicUsize::new(10));
+    vec.insert(3, AtomicUsize::new(11));
+    vec.insert(6, AtomicUsize::new(12));
+    let vals: Vec<usize> = vec
+        .iter()
+        .map(|atomic| atomic.load(Ordering::SeqCst))
+        .collect();
+    assert_eq!(vals, [10, 0, 1, 11, 2, 3, 12]);
+    assert_eq!(vec.remove(3).load(Ordering::SeqCst), 11);
+    assert_eq!(vec.remove(0).load(Ordering::SeqCst), 10);
+    assert_eq!(vec.remove(4).load(Ordering::SeqCst), 12);
+    let vals: Vec<usize> = vec
+        .iter()
+        .map(|atomic| atomic.load(Ordering::SeqCst))
+        .collect();
+    assert_eq!(vals, [0, 1, 2, 3]);
+    while vec.pop().is_some() {}
+    assert!(vec.is_empty());
+}
+
+#[cfg(test)]
+use crate::SharedBox;
+#[cfg(test)]
+use std::convert::TryInto;
+#[cfg(test)]
+use std::sync::atomic::AtomicU64;
+
+#[test]
+fn test_vector_address() {
+    let mut vec = SharedVec::from_iter((0..4u32).map(|i| AtomicU64::new(i.into())));
+    vec.push(AtomicU64::new(4));
+    let address = SharedAddressRange::from(vec);
+    let vec: SharedVec<AtomicU64> = address.try_into().unwrap();
+    assert_eq!(vec.len(), 5);
+    assert_eq!(vec[4].load(Ordering::SeqCst), 4);
+    // Addresses of other types are refused, even if they are the same size
+    let address = SharedAddressRange::from(vec);
+    assert!(matches!(
+        SharedVec::<AtomicUsize>::try_from(address),
+        Err(SharedDataError::WrongType(_))
+    ));
+    assert!(SharedBox::<AtomicU64>::try_from(address).is_err());
+    let vec: SharedVec<AtomicU64> = address.try_into().unwrap();
+    let boxed = SharedBox::new(AtomicU64::new(37));
+    let address = SharedAddressRange::from(boxed);
+    assert!(SharedVec::<AtomicU64>::try_from(address).is_err());
+    assert!(SharedBox::<AtomicUsize>::try_from(address).is_err());
+    let boxed: SharedBox<AtomicU64> = address.try_into().unwrap();
+    assert_eq!(boxed.load(Ordering::SeqCst), 37);
+    drop(vec);
+}
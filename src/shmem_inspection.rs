@@ -0,0 +1,92 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::SegmentStats;
+use crate::SharedAddressRange;
+use std::fmt;
+
+/// A dump of the metadata of a heap of shared memory, for debugging.
+///
+/// This is read while other processes may be using the heap,
+/// so it may not be consistent.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ShmemInspection {
+    pub id: u64,
+    pub name: String,
+    pub num_shmems: usize,
+    /// The shared memory segments in use.
+    pub segments: Vec<SegmentStats>,
+    /// The segments which are slabs of small objects.
+    pub slabs: Vec<SlabInspection>,
+}
+
+/// The state of a slab of objects of the same size class.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SlabInspection {
+    /// The index of the slab's segment.
+    pub index: usize,
+    /// The number of objects which fit in the slab.
+    pub capacity: usize,
+    /// The number of objects which have been bumped off the unused memory at the end of the slab.
+    pub bumped: usize,
+    /// The number of objects which are allocated.
+    pub live: usize,
+    pub free_list: FreeListInspection,
+}
+
+/// The contents of a free list.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FreeListInspection {
+    /// The size of the objects, including their header.
+    pub object_size: u64,
+    pub addresses: Vec<SharedAddressRange>,
+    pub end: FreeListEnd,
+}
+
+/// How the walk of a free list ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FreeListEnd {
+    /// The end of the list was reached.
+    Null,
+    /// The list revisited an earlier address.
+    Cycle,
+    /// The next address could not be read.
+    Unreadable(SharedAddressRange),
+}
+
+impl fmt::Display for ShmemInspection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap {} (id {:#018x})", self.name, self.id)?;
+        writeln!(f, "{} segments", self.num_shmems)?;
+        for segment in &self.segments {
+            writeln!(
+                f,
+                "  segment {}: {} ({} bytes)",
+                segment.index, segment.name, segment.size
+            )?;
+        }
+        for slab in &self.slabs {
+            let free_list = &slab.free_list;
+            writeln!(
+                f,
+                "slab in segment {} of size {}: {} live, {} of {} bumped, {} free",
+                slab.index,
+                free_list.object_size,
+                slab.live,
+                slab.bumped,
+                slab.capacity,
+                free_list.addresses.len()
+            )?;
+            for address in &free_list.addresses {
+                writeln!(f, "  {} ({:#018x})", address, u64::from(*address))?;
+            }
+            match free_list.end {
+                FreeListEnd::Null => (),
+                FreeListEnd::Cycle => writeln!(f, "  cycle!")?,
+                FreeListEnd::Unreadable(address) => writeln!(f, "  unreadable {}!", address)?,
+            }
+        }
+        Ok(())
+    }
+}
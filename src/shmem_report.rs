@@ -0,0 +1,126 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::SharedAddressRange;
+use std::fmt;
+
+/// The result of checking a heap of shared memory for consistency.
+///
+/// Other processes may be using the heap while it is checked,
+/// in which case there may be spurious problems.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ShmemReport {
+    /// The number of free lists checked.
+    pub free_lists: usize,
+    /// The number of free objects checked.
+    pub free_objects: usize,
+    pub problems: Vec<ShmemProblem>,
+}
+
+/// A problem found when checking a heap.
+///
+/// The object size is the size of the objects in the slab whose free list contains the address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ShmemProblem {
+    /// The free list loops back on itself at this address.
+    FreeListCycle {
+        object_size: u64,
+        address: SharedAddressRange,
+    },
+    /// The free object is in a segment which is not in use.
+    UnknownSegment {
+        object_size: u64,
+        address: SharedAddressRange,
+    },
+    /// The free object could not be read, for example because its generation is wrong.
+    Unreadable {
+        object_size: u64,
+        address: SharedAddressRange,
+    },
+    /// The free object is in memory which has not been allocated yet.
+    OverlapsUnused {
+        object_size: u64,
+        address: SharedAddressRange,
+    },
+    /// The free object is on the wrong free list.
+    WrongSizeClass {
+        object_size: u64,
+        address: SharedAddressRange,
+    },
+    /// The free object is in another slab of the same size class.
+    WrongSlab {
+        object_size: u64,
+        address: SharedAddressRange,
+    },
+}
+
+impl ShmemReport {
+    /// Whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for ShmemProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShmemProblem::FreeListCycle {
+                object_size,
+                address,
+            } => write!(f, "free list {} has a cycle at {}", object_size, address),
+            ShmemProblem::UnknownSegment {
+                object_size,
+                address,
+            } => write!(
+                f,
+                "free list {} has {} in an unknown segment",
+                object_size, address
+            ),
+            ShmemProblem::Unreadable {
+                object_size,
+                address,
+            } => write!(f, "free list {} has unreadable {}", object_size, address),
+            ShmemProblem::OverlapsUnused {
+                object_size,
+                address,
+            } => write!(
+                f,
+                "free list {} has {} in unallocated memory",
+                object_size, address
+            ),
+            ShmemProblem::WrongSizeClass {
+                object_size,
+                address,
+            } => write!(
+                f,
+                "free list {} has {} of the wrong size",
+                object_size, address
+            ),
+            ShmemProblem::WrongSlab {
+                object_size,
+                address,
+            } => write!(
+                f,
+                "free list {} has {} from another slab",
+                object_size, address
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ShmemReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "checked {} free objects in {} free lists, {} problems",
+            self.free_objects,
+            self.free_lists,
+            self.problems.len()
+        )?;
+        for problem in &self.problems {
+            writeln!(f, "  {}", problem)?;
+        }
+        Ok(())
+    }
+}
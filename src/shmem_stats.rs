@@ -0,0 +1,50 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+/// A snapshot of the statistics for a heap of shared memory.
+///
+/// The counters are kept in shared memory, so they cover every process using the heap.
+/// They are updated concurrently, so may be slightly out of date.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ShmemStats {
+    /// The shared memory segments in use.
+    pub segments: Vec<SegmentStats>,
+    /// The number of bytes in objects which have been carved out of slabs that are still in use,
+    /// or in whole segments which are still allocated.
+    pub bytes_allocated: u64,
+    /// The size classes which have live or free objects.
+    pub size_classes: Vec<SizeClassStats>,
+}
+
+/// Statistics for a shared memory segment.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SegmentStats {
+    pub index: usize,
+    pub name: String,
+    pub size: usize,
+}
+
+/// Statistics for the objects of one size.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SizeClassStats {
+    /// The size of the objects, including their header,
+    /// or 0 for objects which have shared memory of their own.
+    pub object_size: u64,
+    /// The number of objects which are allocated.
+    pub live: usize,
+    /// The length of the free list.
+    pub free: usize,
+}
+
+impl ShmemStats {
+    /// The total size of the segments.
+    pub fn total_size(&self) -> usize {
+        self.segments.iter().map(|segment| segment.size).sum()
+    }
+
+    /// The number of objects which are allocated.
+    pub fn live_objects(&self) -> usize {
+        self.size_classes.iter().map(|class| class.live).sum()
+    }
+}
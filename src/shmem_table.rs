@@ -0,0 +1,154 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::size_class::NUM_SIZE_CLASSES;
+use crate::unsafe_code;
+use crate::AtomicSlabState;
+use crate::SharedDataError;
+use crate::ShmemName;
+use crate::SizeClass;
+use crate::Volatile;
+use array_macro::array;
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+/// The number of shared memory segments in each table.
+pub(crate) const SHMEMS_PER_TABLE: usize = 64;
+
+// The states of the link to the next table. While the next table is being created,
+// the state also holds the id of the process creating it, so that other processes
+// can take over if it dies, and a count of attempts, so they don't take over
+// from a later attempt by mistake.
+const NO_NEXT: u64 = 0;
+const CREATING_NEXT: u64 = 1;
+const HAS_NEXT: u64 = 2;
+const KIND_MASK: u64 = 0xff;
+const PID_SHIFT: u32 = 8;
+const COUNT_SHIFT: u32 = 40;
+
+fn creating_next(state: u64, pid: u32) -> u64 {
+    let count = (state >> COUNT_SHIFT).wrapping_add(1);
+    (count << COUNT_SHIFT) | ((pid as u64) << PID_SHIFT) | CREATING_NEXT
+}
+
+fn no_next(state: u64) -> u64 {
+    state & !((1 << COUNT_SHIFT) - 1)
+}
+
+/// A table of the shared memory segments used by an allocator.
+///
+/// The first table is stored in the allocator's metadata. Once a table is full,
+/// another table is created in shared memory of its own, and linked from it.
+pub(crate) struct ShmemTable {
+    pub(crate) used: [AtomicBool; SHMEMS_PER_TABLE],
+    // Bumped each time the shared memory in the slot is released, so processes can tell
+    // that their mapping of it is out of date.
+    pub(crate) incarnations: [AtomicU64; SHMEMS_PER_TABLE],
+    pub(crate) names: [Volatile<ShmemName>; SHMEMS_PER_TABLE],
+    pub(crate) sizes: [AtomicUsize; SHMEMS_PER_TABLE],
+    // The generation of the object which fills the shared memory, if there is one,
+    // or else the generation which objects in a slab start at. This is kept here rather
+    // than in the object header, so that it outlives the shared memory, and objects
+    // in the next shared memory in the slot get later generations.
+    pub(crate) generations: [AtomicU64; SHMEMS_PER_TABLE],
+    // The size class of the objects in the shared memory, which is `WHOLE_SHMEM`
+    // unless it is a slab of smaller objects.
+    pub(crate) size_classes: [AtomicU8; SHMEMS_PER_TABLE],
+    // The state of the shared memory, if it is a slab.
+    pub(crate) slabs: [AtomicSlabState; SHMEMS_PER_TABLE],
+    // For each size class, a bit for each slab of that class which may have room for more
+    // objects. These are only hints, so a slab is checked before it is used.
+    pub(crate) partial: [AtomicU64; NUM_SIZE_CLASSES],
+    next_state: AtomicU64,
+    next_name: Volatile<ShmemName>,
+}
+
+impl ShmemTable {
+    pub(crate) fn new() -> ShmemTable {
+        ShmemTable {
+            used: array![AtomicBool::new(false); SHMEMS_PER_TABLE],
+            incarnations: array![AtomicU64::new(0); SHMEMS_PER_TABLE],
+            names: array![Volatile::new(ShmemName::default()); SHMEMS_PER_TABLE],
+            sizes: array![AtomicUsize::new(0); SHMEMS_PER_TABLE],
+            generations: array![AtomicU64::new(0); SHMEMS_PER_TABLE],
+            size_classes: array![AtomicU8::new(SizeClass::WHOLE_SHMEM.0); SHMEMS_PER_TABLE],
+            slabs: array![AtomicSlabState::default(); SHMEMS_PER_TABLE],
+            partial: array![AtomicU64::new(0); NUM_SIZE_CLASSES],
+            next_state: AtomicU64::new(NO_NEXT),
+            next_name: Volatile::new(ShmemName::default()),
+        }
+    }
+
+    /// The name of the shared memory containing the next table, if there is one.
+    pub(crate) fn next_name(&self) -> Option<ShmemName> {
+        if self.next_state.load(Ordering::SeqCst) == HAS_NEXT {
+            Some(self.next_name.read_volatile())
+        } else {
+            None
+        }
+    }
+
+    /// Links the next table, calling `create` to create it if nobody else has,
+    /// and waiting for them if they are, unless they have died.
+    pub(crate) fn link_next(
+        &self,
+        create: impl FnOnce() -> Result<ShmemName, SharedDataError>,
+    ) -> Result<ShmemName, SharedDataError> {
+        loop {
+            let state = self.next_state.load(Ordering::SeqCst);
+            match state & KIND_MASK {
+                NO_NEXT => {
+                    let creating = creating_next(state, process::id());
+                    if self
+                        .next_state
+                        .compare_exchange(state, creating, Ordering::SeqCst, Ordering::SeqCst)
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    return match create() {
+                        Ok(name) => {
+                            self.next_name.write_volatile(name);
+                            self.next_state.store(HAS_NEXT, Ordering::SeqCst);
+                            Ok(name)
+                        }
+                        Err(err) => {
+                            self.next_state.store(no_next(creating), Ordering::SeqCst);
+                            Err(err)
+                        }
+                    };
+                }
+                HAS_NEXT => return Ok(self.next_name.read_volatile()),
+                _ if !unsafe_code::process_is_alive((state >> PID_SHIFT) as u32) => {
+                    let _ = self.next_state.compare_exchange(
+                        state,
+                        no_next(state),
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    );
+                }
+                _ => thread::yield_now(),
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_link_next_after_crash() {
+    let table = ShmemTable::new();
+    // A process which died while creating the next table doesn't block anyone else
+    let mut child = process::Command::new("true").spawn().unwrap();
+    child.wait().unwrap();
+    let state = creating_next(NO_NEXT, child.id());
+    table.next_state.store(state, Ordering::SeqCst);
+    let name = ShmemName::from_str("next").unwrap();
+    assert_eq!(table.link_next(|| Ok(name)).unwrap(), name);
+    assert_eq!(table.next_name(), Some(name));
+}
@@ -0,0 +1,103 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::object_offset::OFFSET_GRANULE;
+use num_traits::ToPrimitive;
+
+#[cfg(feature = "no-panic")]
+use no_panic::no_panic;
+
+// Size classes are stored in 6 bits, so there are at most 64 of them.
+pub(crate) const NUM_SIZE_CLASSES: usize = 64;
+
+// The first classes go up in steps of the offset granule, up to this size.
+const MAX_GRANULE_CLASS: u8 = 3;
+const MAX_GRANULE_SIZE: usize = (MAX_GRANULE_CLASS as usize + 1) * OFFSET_GRANULE as usize;
+const LOG_MAX_GRANULE_SIZE: u32 = 7;
+
+// After that, each power of two is split into four classes, up to this size.
+const MAX_SMALL_CLASS: u8 = 59;
+const MAX_SMALL_SIZE: usize = 2 << 20;
+
+/// The size of an object, including its header.
+///
+/// Small objects are rounded up to 32, 64, 96 or 128 bytes, and then to quarter steps
+/// between powers of two, for example 160, 192, 224 or 256 bytes. This wastes at most
+/// 20% of each object, rather than the 50% wasted by rounding to a power of two.
+///
+/// Objects larger than 2MiB are given a shared memory segment of their own,
+/// whose size is rounded up to a page.
+#[derive(Clone, Copy, Default, Eq, Debug, Ord, PartialEq, PartialOrd)]
+pub struct SizeClass(pub(crate) u8);
+
+impl SizeClass {
+    /// The class of objects which fill a shared memory segment of their own.
+    pub const WHOLE_SHMEM: SizeClass = SizeClass(63);
+
+    /// The smallest size class which holds `size` bytes.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn ceil(size: usize) -> SizeClass {
+        if size <= MAX_GRANULE_SIZE {
+            SizeClass((size.saturating_sub(1) as u64 / OFFSET_GRANULE) as u8)
+        } else if size <= MAX_SMALL_SIZE {
+            // The size is between 2^log and 2^(log+1), which is split into quarters.
+            let log = 63 - (size - 1).leading_zeros();
+            let quarter = (size - 1 - (1 << log)) >> (log - 2);
+            let class = (log - LOG_MAX_GRANULE_SIZE) * 4 + quarter as u32;
+            SizeClass(MAX_GRANULE_CLASS + 1 + class as u8)
+        } else {
+            SizeClass::WHOLE_SHMEM
+        }
+    }
+
+    /// Whether objects of this class fill a shared memory segment of their own.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn is_whole_shmem(self) -> bool {
+        self == SizeClass::WHOLE_SHMEM
+    }
+}
+
+impl ToPrimitive for SizeClass {
+    // The size of objects of this class, or `None` if it depends on their segment.
+    fn to_u64(&self) -> Option<u64> {
+        if self.0 <= MAX_GRANULE_CLASS {
+            Some((self.0 as u64 + 1) * OFFSET_GRANULE)
+        } else if self.0 <= MAX_SMALL_CLASS {
+            let class = (self.0 - MAX_GRANULE_CLASS - 1) as u32;
+            let log = LOG_MAX_GRANULE_SIZE + class / 4;
+            let quarter = (class % 4) as u64 + 1;
+            Some((1 << log) + (quarter << (log - 2)))
+        } else {
+            None
+        }
+    }
+
+    fn to_i64(&self) -> Option<i64> {
+        self.to_u64().as_ref().and_then(ToPrimitive::to_i64)
+    }
+}
+
+#[test]
+fn test_size_classes() {
+    let sizes: Vec<u64> = (0..12)
+        .filter_map(|class| SizeClass(class).to_u64())
+        .collect();
+    assert_eq!(
+        sizes,
+        [32, 64, 96, 128, 160, 192, 224, 256, 320, 384, 448, 512]
+    );
+    assert_eq!(SizeClass(MAX_SMALL_CLASS).to_usize(), Some(MAX_SMALL_SIZE));
+    assert_eq!(SizeClass::WHOLE_SHMEM.to_u64(), None);
+    assert_eq!(SizeClass::ceil(0), SizeClass(0));
+    assert_eq!(SizeClass::ceil(MAX_SMALL_SIZE + 1), SizeClass::WHOLE_SHMEM);
+    let mut previous = 0;
+    for class in 0..=MAX_SMALL_CLASS {
+        let size = SizeClass(class).to_usize().unwrap();
+        assert_eq!(size as u64 % OFFSET_GRANULE, 0);
+        assert!(previous < size);
+        assert_eq!(SizeClass::ceil(size), SizeClass(class));
+        assert_eq!(SizeClass::ceil(previous + 1), SizeClass(class));
+        previous = size;
+    }
+}
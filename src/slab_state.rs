@@ -0,0 +1,169 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+#[cfg(feature = "no-panic")]
+use no_panic::no_panic;
+
+// The free list head, the number of bumped objects and the number of live objects
+// are each stored in this many bits, which limits the number of objects in a slab.
+// The rest of the bits go to the tag, so this is no bigger than slabs need.
+const INDEX_BITS: u32 = 14;
+const INDEX_MASK: u64 = (1 << INDEX_BITS) - 1;
+const HEAD_SHIFT: u32 = 0;
+const BUMPED_SHIFT: u32 = INDEX_BITS;
+const LIVE_SHIFT: u32 = 2 * INDEX_BITS;
+const RELEASED: u64 = 1 << (3 * INDEX_BITS);
+const TAG_SHIFT: u32 = 3 * INDEX_BITS + 1;
+
+/// The most objects a slab can hold. The head of the free list is stored as its index
+/// plus one, so that zero means the list is empty.
+pub(crate) const MAX_SLAB_OBJECTS: usize = INDEX_MASK as usize;
+
+/// The state of a slab of objects of the same size class.
+///
+/// This is packed into 64 bits, so it can be updated atomically, as 14 bits each
+/// of the head of the free list, the number of objects which have been bumped off
+/// the unused memory at the end of the slab, and the number of live objects,
+/// a bit which is set once the slab has been released, and 21 bits of tag.
+///
+/// The tag is changed every time the state is, so a compare-and-swap with a stale state fails,
+/// even if the same object has been popped and pushed back in the meantime. The tag wraps
+/// round after 2097152 changes, so this only protects against ABA as long as no thread stalls
+/// between reading the state and updating it while the state changes a multiple of 2097152
+/// times and ends up with the same head.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SlabState(u64);
+
+impl SlabState {
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    fn field(self, shift: u32) -> usize {
+        ((self.0 >> shift) & INDEX_MASK) as usize
+    }
+
+    // The state with the given fields, and the next tag.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    fn next(self, head: Option<usize>, bumped: usize, live: usize, released: bool) -> SlabState {
+        let head = head.map_or(0, |head| head as u64 + 1) & INDEX_MASK;
+        let bumped = bumped as u64 & INDEX_MASK;
+        let live = live as u64 & INDEX_MASK;
+        let released = if released { RELEASED } else { 0 };
+        let tag = (self.0 >> TAG_SHIFT).wrapping_add(1) << TAG_SHIFT;
+        SlabState(
+            (head << HEAD_SHIFT) | (bumped << BUMPED_SHIFT) | (live << LIVE_SHIFT) | released | tag,
+        )
+    }
+
+    /// The index of the object at the head of the free list, if it isn't empty.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn head(self) -> Option<usize> {
+        self.field(HEAD_SHIFT).checked_sub(1)
+    }
+
+    /// The number of objects which have been bumped off the unused memory.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn bumped(self) -> usize {
+        self.field(BUMPED_SHIFT)
+    }
+
+    /// The number of objects which are allocated.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn live(self) -> usize {
+        self.field(LIVE_SHIFT)
+    }
+
+    /// Whether the slab has been released, after all of its objects were freed.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn is_released(self) -> bool {
+        self.0 & RELEASED != 0
+    }
+
+    /// The state of a new slab, whose first object has been allocated.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn reset(self) -> SlabState {
+        self.next(None, 1, 1, false)
+    }
+
+    /// The state after allocating the head of the free list, whose successor is `next`.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn pop(self, next: Option<usize>) -> SlabState {
+        self.next(next, self.bumped(), self.live() + 1, false)
+    }
+
+    /// The state after allocating an object from the unused memory.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn bump(self) -> SlabState {
+        self.next(self.head(), self.bumped() + 1, self.live() + 1, false)
+    }
+
+    /// The state after freeing the object at `index`, which becomes the head of the free list.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn push(self, index: usize) -> SlabState {
+        self.next(
+            Some(index),
+            self.bumped(),
+            self.live().saturating_sub(1),
+            false,
+        )
+    }
+
+    /// The state after freeing the last live object, once every object has been bumped,
+    /// so the slab's memory can be released.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn release(self) -> SlabState {
+        self.next(None, self.bumped(), 0, true)
+    }
+}
+
+#[derive(Default)]
+pub struct AtomicSlabState(AtomicU64);
+
+impl AtomicSlabState {
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn load(&self, order: Ordering) -> SlabState {
+        SlabState(self.0.load(order))
+    }
+
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn store(&self, state: SlabState, order: Ordering) {
+        self.0.store(state.0, order)
+    }
+
+    /// Replaces the state by `new`, as long as the state is still `current`.
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    pub fn compare_and_set(&self, current: SlabState, new: SlabState) -> bool {
+        self.0
+            .compare_exchange(current.0, new.0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+}
+
+#[test]
+fn test_slab_state() {
+    let state = SlabState::default().reset();
+    assert_eq!((state.head(), state.bumped(), state.live()), (None, 1, 1));
+    let state = state.bump().push(0);
+    assert_eq!(
+        (state.head(), state.bumped(), state.live()),
+        (Some(0), 2, 1)
+    );
+    // Popping and pushing back the same object changes the tag
+    let popped = state.pop(None);
+    assert_eq!((popped.head(), popped.live()), (None, 2));
+    assert_ne!(popped.push(0), state);
+    assert_eq!(popped.push(0).head(), state.head());
+    // The tag only wraps round after 2^21 changes
+    let mut changed = state;
+    for _ in 1..(1 << 20) {
+        changed = changed.pop(None).push(0);
+    }
+    assert_ne!(changed, state);
+    assert_eq!(changed.pop(None).push(0), state);
+    let released = state.release();
+    assert!(released.is_released());
+    assert_eq!((released.head(), released.live()), (None, 0));
+    assert!(!released.reset().is_released());
+}
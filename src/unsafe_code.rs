@@ -4,41 +4,117 @@
 
 #![allow(unsafe_code)]
 
+use array_macro::array;
+use lazy_static::lazy_static;
 use owning_ref::StableAddress;
-use shared_memory::EventSet;
-use shared_memory::EventState;
-use shared_memory::EventWait;
 use shared_memory::SharedMem;
-use shared_memory::SharedMemCast;
-use shared_memory::Timeout;
+#[cfg(not(unix))]
+use shared_memory::SharedMemConf;
 use std::cell::UnsafeCell;
+#[cfg(unix)]
+use std::convert::TryFrom;
+#[cfg(unix)]
+use std::ffi::CString;
+#[cfg(unix)]
+use std::io;
+use std::marker::PhantomData;
 use std::mem;
 use std::ops::Deref;
 use std::ptr;
 use std::slice;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI16;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::AtomicI8;
+use std::sync::atomic::AtomicIsize;
 use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::AtomicU16;
+use std::sync::atomic::AtomicU32;
 use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicU8;
 use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::time::Duration;
 
+use crate::allocator::ObjectHeader;
 use crate::allocator::ShmemMetadata;
 use crate::shared_channel::SharedChannel;
+use crate::shared_channel::SharedChannelCounts;
 use crate::shared_channel::SharedReceiver;
 use crate::shared_channel::SharedSender;
+use crate::shared_futex::SharedFutex;
+use crate::shared_hash_map::SharedHashMapSlot;
+use crate::shared_header::SharedHeader;
+use crate::shared_mutex::SharedMutex;
 use crate::shared_rc::SharedRcContents;
-use crate::AtomicSharedAddress;
+use crate::shared_root::SharedRoot;
+use crate::shmem_table::ShmemTable;
+use crate::AllocatorId;
 use crate::AtomicSharedAddressRange;
+use crate::AtomicSlabState;
 use crate::ObjectOffset;
-use crate::ObjectSize;
-use crate::SharedAddress;
 use crate::SharedAddressRange;
 use crate::SharedBox;
+use crate::SharedDataError;
+use crate::SharedHashMap;
 use crate::SharedOption;
 use crate::SharedRc;
+use crate::SharedString;
 use crate::SharedVec;
+use crate::SharedWeak;
+use crate::ShmemAllocator;
 use crate::ShmemId;
 use crate::ShmemName;
 
+/// A marker trait for types that can be stored in shared memory.
+///
+/// Only implement this trait if the type has no pointers into process-local memory,
+/// does not allocate or free memory, and every bit pattern that another process might
+/// write is a valid value of the type. In particular, `Vec` should not implement this trait,
+/// and nor should `bool` or `char`, which have invalid bit patterns, or `AtomicPtr`,
+/// which points into process-local memory. Use `AtomicBool`, `u32` or a `SharedAddressRange`
+/// instead.
+///
+/// ```compile_fail
+/// # use shared_data::SharedBox;
+/// let boxed = SharedBox::new(true);
+/// ```
+///
+/// Rather than implementing this trait by hand, use `#[derive(SharedMemCast)]`.
+pub unsafe trait SharedMemCast {}
+
+unsafe impl SharedMemCast for () {}
+unsafe impl SharedMemCast for f32 {}
+unsafe impl SharedMemCast for f64 {}
+unsafe impl SharedMemCast for i8 {}
+unsafe impl SharedMemCast for i16 {}
+unsafe impl SharedMemCast for i32 {}
+unsafe impl SharedMemCast for i64 {}
+unsafe impl SharedMemCast for i128 {}
+unsafe impl SharedMemCast for isize {}
+unsafe impl SharedMemCast for u8 {}
+unsafe impl SharedMemCast for u16 {}
+unsafe impl SharedMemCast for u32 {}
+unsafe impl SharedMemCast for u64 {}
+unsafe impl SharedMemCast for u128 {}
+unsafe impl SharedMemCast for usize {}
+unsafe impl SharedMemCast for AtomicBool {}
+unsafe impl SharedMemCast for AtomicI8 {}
+unsafe impl SharedMemCast for AtomicI16 {}
+unsafe impl SharedMemCast for AtomicI32 {}
+unsafe impl SharedMemCast for AtomicI64 {}
+unsafe impl SharedMemCast for AtomicIsize {}
+unsafe impl SharedMemCast for AtomicU8 {}
+unsafe impl SharedMemCast for AtomicU16 {}
+unsafe impl SharedMemCast for AtomicU32 {}
+unsafe impl SharedMemCast for AtomicU64 {}
+unsafe impl SharedMemCast for AtomicUsize {}
+unsafe impl<T: ?Sized> SharedMemCast for PhantomData<T> {}
+unsafe impl<T: SharedMemCast, const N: usize> SharedMemCast for [T; N] {}
+
 /// A marker trait for types that it's safe to take a reference to in shared memory.
 ///
 /// This is more restrictive than `SharedMemoryCast`, for example even though `usize` is
@@ -57,97 +133,317 @@ use crate::ShmemName;
 /// `SharedMemRef` is to `SharedMemCast` as `Sync` is to `Send`.
 pub unsafe trait SharedMemRef {}
 
+unsafe impl SharedMemRef for () {}
 unsafe impl SharedMemRef for AtomicBool {}
-unsafe impl SharedMemRef for AtomicUsize {}
+unsafe impl SharedMemRef for AtomicI8 {}
+unsafe impl SharedMemRef for AtomicI16 {}
+unsafe impl SharedMemRef for AtomicI32 {}
+unsafe impl SharedMemRef for AtomicI64 {}
+unsafe impl SharedMemRef for AtomicIsize {}
+unsafe impl SharedMemRef for AtomicU8 {}
+unsafe impl SharedMemRef for AtomicU16 {}
+unsafe impl SharedMemRef for AtomicU32 {}
 unsafe impl SharedMemRef for AtomicU64 {}
-unsafe impl<T> SharedMemRef for AtomicPtr<T> {}
-// etc.
-
-unsafe impl SharedMemRef for () {}
-unsafe impl<T1> SharedMemRef for (T1,) where T1: SharedMemRef {}
-unsafe impl<T1, T2> SharedMemRef for (T1, T2)
-where
-    T1: SharedMemRef,
-    T2: SharedMemRef,
-{
-}
-unsafe impl<T1, T2, T3> SharedMemRef for (T1, T2, T3)
-where
-    T1: SharedMemRef,
-    T2: SharedMemRef,
-    T3: SharedMemRef,
-{
+unsafe impl SharedMemRef for AtomicUsize {}
+unsafe impl<T: ?Sized> SharedMemRef for PhantomData<T> {}
+unsafe impl<T: SharedMemRef, const N: usize> SharedMemRef for [T; N] {}
+
+// Tuples are `SharedMemCast` or `SharedMemRef` if all their components are.
+macro_rules! tuple_impls {
+    ($($T:ident),+) => {
+        unsafe impl<$($T: SharedMemCast),+> SharedMemCast for ($($T,)+) {}
+        unsafe impl<$($T: SharedMemRef),+> SharedMemRef for ($($T,)+) {}
+    };
 }
-// etc
 
-unsafe impl SharedMemRef for AtomicSharedAddress {}
+tuple_impls!(T1);
+tuple_impls!(T1, T2);
+tuple_impls!(T1, T2, T3);
+tuple_impls!(T1, T2, T3, T4);
+tuple_impls!(T1, T2, T3, T4, T5);
+tuple_impls!(T1, T2, T3, T4, T5, T6);
+tuple_impls!(T1, T2, T3, T4, T5, T6, T7);
+tuple_impls!(T1, T2, T3, T4, T5, T6, T7, T8);
+tuple_impls!(T1, T2, T3, T4, T5, T6, T7, T8, T9);
+tuple_impls!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10);
+tuple_impls!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11);
+tuple_impls!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12);
+
+// Implementations of `SharedMemRef` for types in this crate
+unsafe impl SharedMemRef for AtomicSlabState {}
+unsafe impl SharedMemRef for SharedRoot {}
+unsafe impl SharedMemRef for SharedHeader {}
+unsafe impl SharedMemRef for ObjectHeader {}
 unsafe impl SharedMemRef for AtomicSharedAddressRange {}
 unsafe impl SharedMemRef for ShmemMetadata {}
+unsafe impl SharedMemRef for ShmemTable {}
 unsafe impl<T: SharedMemCast> SharedMemRef for SharedChannel<T> {}
+unsafe impl SharedMemRef for SharedChannelCounts {}
+unsafe impl SharedMemRef for SharedFutex {}
+unsafe impl<T: SharedMemCast> SharedMemRef for SharedMutex<T> {}
 unsafe impl<T: SharedMemCast> SharedMemRef for SharedOption<T> {}
 unsafe impl<T: SharedMemCast> SharedMemRef for SharedRc<T> {}
 unsafe impl<T: SharedMemCast> SharedMemRef for SharedRcContents<T> {}
+unsafe impl<T: SharedMemCast> SharedMemRef for SharedWeak<T> {}
 unsafe impl<T: SharedMemCast> SharedMemRef for SharedReceiver<T> {}
 unsafe impl<T: SharedMemCast> SharedMemRef for SharedSender<T> {}
 unsafe impl<T: SharedMemCast> SharedMemRef for SharedVec<T> {}
+unsafe impl<K: SharedMemCast, V: SharedMemCast> SharedMemRef for SharedHashMap<K, V> {}
+unsafe impl<K: SharedMemCast, V: SharedMemCast> SharedMemRef for SharedHashMapSlot<K, V> {}
+unsafe impl SharedMemRef for SharedString {}
 unsafe impl<T: SharedMemCast> SharedMemRef for Volatile<T> {}
 
 // Implementations of `SharedMemCast` for types in this crate
-unsafe impl SharedMemCast for AtomicSharedAddress {}
+unsafe impl SharedMemCast for AllocatorId {}
+unsafe impl SharedMemCast for AtomicSlabState {}
+unsafe impl SharedMemCast for SharedRoot {}
+unsafe impl SharedMemCast for SharedHeader {}
+unsafe impl SharedMemCast for ObjectHeader {}
 unsafe impl SharedMemCast for AtomicSharedAddressRange {}
 unsafe impl SharedMemCast for ObjectOffset {}
-unsafe impl SharedMemCast for ObjectSize {}
-unsafe impl SharedMemCast for SharedAddress {}
 unsafe impl SharedMemCast for SharedAddressRange {}
 unsafe impl SharedMemCast for ShmemId {}
 unsafe impl SharedMemCast for ShmemMetadata {}
+unsafe impl SharedMemCast for ShmemTable {}
 unsafe impl SharedMemCast for ShmemName {}
 unsafe impl<T: SharedMemCast> SharedMemCast for SharedBox<T> {}
 unsafe impl<T: SharedMemCast> SharedMemCast for SharedChannel<T> {}
+unsafe impl SharedMemCast for SharedChannelCounts {}
+unsafe impl SharedMemCast for SharedFutex {}
+unsafe impl<T: SharedMemCast> SharedMemCast for SharedMutex<T> {}
 unsafe impl<T: SharedMemCast> SharedMemCast for SharedOption<T> {}
 unsafe impl<T: SharedMemCast> SharedMemCast for SharedRc<T> {}
 unsafe impl<T: SharedMemCast> SharedMemCast for SharedRcContents<T> {}
+unsafe impl<T: SharedMemCast> SharedMemCast for SharedWeak<T> {}
 unsafe impl<T: SharedMemCast> SharedMemCast for SharedReceiver<T> {}
 unsafe impl<T: SharedMemCast> SharedMemCast for SharedSender<T> {}
 unsafe impl<T: SharedMemCast> SharedMemCast for SharedVec<T> {}
+unsafe impl<K: SharedMemCast, V: SharedMemCast> SharedMemCast for SharedHashMap<K, V> {}
+unsafe impl<K: SharedMemCast, V: SharedMemCast> SharedMemCast for SharedHashMapSlot<K, V> {}
+unsafe impl SharedMemCast for SharedString {}
 unsafe impl<T: SharedMemCast> SharedMemCast for Volatile<T> {}
 
 // `Volatile` is `Send` and `Sync`.
 unsafe impl<T: SharedMemCast> Sync for Volatile<T> {}
 unsafe impl<T: SharedMemCast> Send for Volatile<T> {}
 
-/// A wrapper round the `SharedMem` type which implements `Sync`.
-pub struct SyncSharedMem(*mut Volatile<u8>, usize, SharedMem);
+/// A mapping of shared memory which implements `Sync`.
+///
+/// The memory is either mapped by the shared memory crate, or on Unix it may be
+/// shared memory which we created or opened ourselves, see `create` and `open`.
+pub struct SyncSharedMem {
+    ptr: *mut Volatile<u8>,
+    size: usize,
+    // The `SharedMem`, if the memory was mapped by the shared memory crate.
+    // It is only kept so that it gets unmapped when this is dropped.
+    #[allow(dead_code)]
+    shmem: Option<SharedMem>,
+}
 
 impl SyncSharedMem {
     /// Create a new `SyncSharedMem` from a `SharedMem`.
     pub fn from_shmem(shmem: SharedMem) -> SyncSharedMem {
         let ptr = shmem.get_ptr() as *mut Volatile<u8>;
         let size = shmem.get_size();
-        let result = SyncSharedMem(ptr, size, shmem);
+        SyncSharedMem {
+            ptr,
+            size,
+            shmem: Some(shmem),
+        }
+    }
+
+    /// Creates shared memory with a new name, and maps it.
+    ///
+    /// Unlike the shared memory crate, this doesn't keep the shared memory open,
+    /// so once it is unlinked, the OS frees it when the last process unmaps it.
+    #[cfg(unix)]
+    pub fn create(size: usize) -> Result<(SyncSharedMem, ShmemName), SharedDataError> {
+        loop {
+            let name = format!("/shared_data_{:016x}", rand::random::<u64>());
+            let name = ShmemName::from_str(&name).ok_or(SharedDataError::NameTooLong(name))?;
+            let c_name = c_name(name)?;
+            let fd = unsafe {
+                libc::shm_open(
+                    c_name.as_ptr(),
+                    libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                    0o600,
+                )
+            };
+            if fd < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::AlreadyExists {
+                    continue;
+                }
+                return Err(SharedDataError::shmem(Box::new(err)));
+            }
+            let result = match libc::off_t::try_from(size) {
+                Err(_) => Err(SharedDataError::TooLarge(size)),
+                Ok(len) if unsafe { libc::ftruncate(fd, len) } != 0 => Err(last_os_error()),
+                Ok(_) => SyncSharedMem::map(fd, size),
+            };
+            unsafe { libc::close(fd) };
+            if result.is_err() {
+                SyncSharedMem::unlink(name);
+            }
+            return result.map(|shmem| (shmem, name));
+        }
+    }
+
+    /// Creates shared memory with a new name, and maps it.
+    #[cfg(not(unix))]
+    pub fn create(size: usize) -> Result<(SyncSharedMem, ShmemName), SharedDataError> {
+        let shmem = SharedMemConf::new()
+            .set_size(size)
+            .create()
+            .map_err(SharedDataError::shmem)?;
+        let name = ShmemName::from_str(shmem.get_os_path())
+            .ok_or_else(|| SharedDataError::NameTooLong(String::from(shmem.get_os_path())))?;
+        Ok((SyncSharedMem::from_shmem(shmem), name))
+    }
+
+    /// Opens shared memory made by `create`, which is at least the given size, and maps it.
+    #[cfg(unix)]
+    pub fn open(name: ShmemName, size: usize) -> Result<SyncSharedMem, SharedDataError> {
+        let c_name = c_name(name)?;
+        let fd = unsafe { libc::shm_open(c_name.as_ptr(), libc::O_RDWR, 0) };
+        if fd < 0 {
+            return Err(last_os_error());
+        }
+        // Mapping past the end of the shared memory would fault when it is accessed.
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        let result = if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+            Err(last_os_error())
+        } else if (stat.st_size as u64) < size as u64 {
+            Err(SharedDataError::TooSmall {
+                size: stat.st_size as usize,
+                required: size,
+            })
+        } else {
+            SyncSharedMem::map(fd, size)
+        };
+        unsafe { libc::close(fd) };
         result
     }
 
-    /// Set an event
-    pub fn set_event(&self, index: usize, state: EventState) {
-        // Very annoyingly, we have to do this INCREDIBLY UNSAFE cast,
-        // because set takes a &mut self, even though it never uses
-        // the fact it's &mut. It would be nice if there was a way
-        // to use events safely without locking.
-        let this = &self.2 as *const SharedMem as *mut SharedMem;
-        let this = unsafe { &mut *this };
-        let _ = this.set(index, state);
+    /// Opens shared memory made by `create`, and maps it.
+    #[cfg(not(unix))]
+    pub fn open(name: ShmemName, _size: usize) -> Result<SyncSharedMem, SharedDataError> {
+        let shmem = SharedMem::open(name.as_str()).map_err(SharedDataError::shmem)?;
+        Ok(SyncSharedMem::from_shmem(shmem))
+    }
+
+    #[cfg(unix)]
+    fn map(fd: libc::c_int, size: usize) -> Result<SyncSharedMem, SharedDataError> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(last_os_error());
+        }
+        Ok(SyncSharedMem {
+            ptr: ptr as *mut Volatile<u8>,
+            size,
+            shmem: None,
+        })
+    }
+
+    /// Unlinks shared memory made by `create`, so no more processes can open it.
+    ///
+    /// The OS keeps count of the processes which have it mapped, and frees it
+    /// once they have all unmapped it.
+    #[cfg(unix)]
+    pub fn unlink(name: ShmemName) {
+        if let Ok(c_name) = c_name(name) {
+            unsafe { libc::shm_unlink(c_name.as_ptr()) };
+        }
+    }
+
+    /// Unlinks shared memory made by `create`, which the shared memory crate
+    /// only does when the process which created it unmaps it.
+    #[cfg(not(unix))]
+    pub fn unlink(_name: ShmemName) {}
+
+    /// Replaces this process's mapping by private zero pages, so that it no longer
+    /// keeps the shared memory alive. Slices of the mapping stay valid,
+    /// but they no longer see writes by other processes.
+    ///
+    /// Memory mapped by the shared memory crate is left alone, since it keeps
+    /// the shared memory open anyway.
+    #[cfg(unix)]
+    pub fn release(&self) {
+        if self.shmem.is_some() {
+            return;
+        }
+        unsafe {
+            libc::mmap(
+                self.ptr as *mut libc::c_void,
+                self.size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                -1,
+                0,
+            );
+        }
+    }
+
+    /// Releases this process's mapping, which is only supported on Unix.
+    #[cfg(not(unix))]
+    pub fn release(&self) {}
+
+    /// Makes the mapping read-only in this process, so any write to it faults.
+    ///
+    /// The mapping starts on a page boundary before the memory, so the pages
+    /// are rounded out to cover all of it.
+    #[cfg(target_os = "linux")]
+    pub fn make_read_only(&self) -> Result<(), SharedDataError> {
+        let page_size = 4096;
+        let start = self.ptr as usize;
+        let page_start = start / page_size * page_size;
+        let result = unsafe {
+            libc::mprotect(
+                page_start as *mut libc::c_void,
+                start + self.size - page_start,
+                libc::PROT_READ,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(last_os_error())
+        }
+    }
+
+    /// Makes the mapping read-only in this process, which is only supported on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub fn make_read_only(&self) -> Result<(), SharedDataError> {
+        Ok(())
     }
+}
 
-    /// Wait for an event
-    pub fn wait_event(&self, index: usize, timeout: Timeout) {
-        // Very annoyingly, we have to do this INCREDIBLY UNSAFE cast,
-        // because wait takes a &mut self, even though it never uses
-        // the fact it's &mut. It would be nice if there was a way
-        // to use events safely without locking.
-        let this = &self.2 as *const SharedMem as *mut SharedMem;
-        let this = unsafe { &mut *this };
-        let _ = this.wait(index, timeout);
+#[cfg(unix)]
+fn c_name(name: ShmemName) -> Result<CString, SharedDataError> {
+    CString::new(name.as_str()).map_err(|err| SharedDataError::shmem(Box::new(err)))
+}
+
+#[cfg(unix)]
+fn last_os_error() -> SharedDataError {
+    SharedDataError::shmem(Box::new(io::Error::last_os_error()))
+}
+
+impl Drop for SyncSharedMem {
+    fn drop(&mut self) {
+        // Memory mapped by the shared memory crate is unmapped when the `SharedMem` is dropped.
+        #[cfg(unix)]
+        if self.shmem.is_none() {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.size) };
+        }
     }
 }
 
@@ -155,13 +451,157 @@ impl Deref for SyncSharedMem {
     type Target = [Volatile<u8>];
 
     fn deref(&self) -> &[Volatile<u8>] {
-        unsafe { slice::from_raw_parts(self.0, self.1) }
+        unsafe { slice::from_raw_parts(self.ptr, self.size) }
     }
 }
 
 unsafe impl Sync for SyncSharedMem {}
+unsafe impl Send for SyncSharedMem {}
 unsafe impl StableAddress for SyncSharedMem {}
 
+/// This process's mapping of a shared memory segment, which is replaced
+/// when the segment is released and its slot is reused.
+///
+/// Replaced mappings have their pages released, but they are only unmapped
+/// when this is dropped, since slices of them may still be in use.
+pub(crate) struct LocalShmem {
+    current: AtomicPtr<MappedShmem>,
+    // Boxed, so that references to them stay valid as the list grows.
+    #[allow(clippy::vec_box)]
+    replaced: Mutex<Vec<Box<MappedShmem>>>,
+}
+
+/// A mapping of a shared memory segment, for an incarnation of its slot.
+pub(crate) struct MappedShmem {
+    pub(crate) incarnation: u64,
+    pub(crate) shmem: SyncSharedMem,
+    released: AtomicBool,
+}
+
+impl MappedShmem {
+    pub(crate) fn new(incarnation: u64, shmem: SyncSharedMem) -> MappedShmem {
+        MappedShmem {
+            incarnation,
+            shmem,
+            released: AtomicBool::new(false),
+        }
+    }
+
+    /// Releases the pages of the mapping, unless they have been already.
+    pub(crate) fn release(&self) {
+        if !self.released.swap(true, Ordering::SeqCst) {
+            self.shmem.release();
+        }
+    }
+}
+
+impl LocalShmem {
+    pub(crate) fn new() -> LocalShmem {
+        LocalShmem {
+            current: AtomicPtr::new(ptr::null_mut()),
+            replaced: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The current mapping, which lives as long as this does.
+    pub(crate) fn get(&self) -> Option<&MappedShmem> {
+        unsafe { self.current.load(Ordering::SeqCst).as_ref() }
+    }
+
+    /// Replaces the `current` mapping by a new one, unless another thread beat us to it,
+    /// returning whichever mapping is now current.
+    pub(crate) fn replace(
+        &self,
+        current: Option<&MappedShmem>,
+        new: MappedShmem,
+    ) -> Option<&MappedShmem> {
+        let current = current.map_or(ptr::null_mut(), |current| {
+            current as *const MappedShmem as *mut MappedShmem
+        });
+        let new = Box::into_raw(Box::new(new));
+        match self
+            .current
+            .compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(replaced) => {
+                if !replaced.is_null() {
+                    let replaced = unsafe { Box::from_raw(replaced) };
+                    replaced.release();
+                    let mut list = self.replaced.lock().unwrap_or_else(PoisonError::into_inner);
+                    list.push(replaced);
+                }
+                unsafe { new.as_ref() }
+            }
+            Err(current) => {
+                drop(unsafe { Box::from_raw(new) });
+                unsafe { current.as_ref() }
+            }
+        }
+    }
+}
+
+impl Drop for LocalShmem {
+    fn drop(&mut self) {
+        let current = *self.current.get_mut();
+        if !current.is_null() {
+            drop(unsafe { Box::from_raw(current) });
+        }
+    }
+}
+
+/// Waits for a wake up on a futex, as long as it contains `expected`.
+///
+/// Futexes are shared between processes, as long as they are in shared memory.
+/// Wakeups may be spurious.
+#[cfg(target_os = "linux")]
+pub fn futex_wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let timeout = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timeout_ptr = match timeout {
+        Some(ref timeout) => timeout as *const libc::timespec,
+        None => ptr::null(),
+    };
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            futex as *const AtomicU32,
+            libc::FUTEX_WAIT,
+            expected,
+            timeout_ptr,
+        );
+    }
+}
+
+/// Wakes up to `count` processes waiting on a futex.
+#[cfg(target_os = "linux")]
+pub fn futex_wake(futex: &AtomicU32, count: i32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            futex as *const AtomicU32,
+            libc::FUTEX_WAKE,
+            count,
+        );
+    }
+}
+
+/// Whether a process is still running, so that other processes can recover
+/// if it dies while it is part way through changing shared memory.
+#[cfg(target_os = "linux")]
+pub fn process_is_alive(pid: u32) -> bool {
+    // Sending signal 0 only checks whether the process exists.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// Whether a process is still running, which is assumed on platforms other than Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn process_is_alive(_pid: u32) -> bool {
+    true
+}
+
 /// Data stored in memory that can be changed
 /// at any time, for example shared memory.
 ///
@@ -247,39 +687,189 @@ impl<T: SharedMemCast + SharedMemRef> Deref for Volatile<T> {
     }
 }
 
-/// Convert a slice of volatile values that implement `SharedMemRef`
-/// into a slice of values.
-pub fn slice_from_volatile<T: SharedMemCast + SharedMemRef>(slice: &[Volatile<T>]) -> &[T] {
-    unsafe { mem::transmute(slice) }
+impl ShmemAllocator {
+    /// Closes an allocator, unmapping its shared memory.
+    ///
+    /// Containers allocated by it report `UnknownAllocator` afterwards.
+    /// If this process created the heap, or any of its shared memory,
+    /// that shared memory is unlinked, so other processes can no longer open it.
+    ///
+    /// # Safety
+    ///
+    /// The allocator must not be used once it is closed, and nothing borrowed from it,
+    /// such as the bytes returned by `get_bytes` or the contents of a container,
+    /// may still be in use. It must not be the bootstrap allocator.
+    pub unsafe fn close(alloc: &'static ShmemAllocator) {
+        drop(ALLOCATORS.unregister(alloc));
+    }
 }
 
-// Is there a way to do this with safe code?
-pub(crate) fn slice_empty<'a, T: 'a>() -> &'a [T] {
-    unsafe { slice::from_raw_parts(ptr::NonNull::dangling().as_ptr(), 0) }
+// The number of allocators a process can have open at once.
+const MAX_ALLOCATORS: usize = 64;
+
+/// The allocators this process has open.
+///
+/// Containers find their allocator by id on every access, so that doesn't lock.
+/// Each allocator goes in the first free entry at or after the one its id picks,
+/// so it is usually found straight away, and only a missing id checks every entry.
+pub(crate) struct AllocatorRegistry {
+    // Held while allocators are registered and unregistered.
+    lock: Mutex<()>,
+    entries: [RegisteredAllocator; MAX_ALLOCATORS],
 }
 
-// Various conversions between `u64` and shared addresses.
+struct RegisteredAllocator {
+    id: AtomicU64,
+    // A leaked box, which is only freed once it has been taken out of the entry.
+    alloc: AtomicPtr<ShmemAllocator>,
+}
 
-impl From<u64> for SharedAddress {
-    fn from(data: u64) -> SharedAddress {
-        unsafe { mem::transmute(data) }
+impl RegisteredAllocator {
+    // The allocator in this entry, if it has the given id. This is only freed
+    // when the allocator is closed, which nobody may do while they are using it.
+    fn get(&self, id: AllocatorId) -> Option<&'static ShmemAllocator> {
+        if self.id.load(Ordering::SeqCst) != id.to_u64() {
+            return None;
+        }
+        let alloc = self.alloc.load(Ordering::SeqCst);
+        if alloc.is_null() || self.id.load(Ordering::SeqCst) != id.to_u64() {
+            return None;
+        }
+        unsafe { alloc.as_ref() }
     }
 }
 
-impl From<SharedAddress> for u64 {
-    fn from(address: SharedAddress) -> u64 {
-        unsafe { mem::transmute(address) }
+impl AllocatorRegistry {
+    fn new() -> AllocatorRegistry {
+        AllocatorRegistry {
+            lock: Mutex::new(()),
+            entries: array![RegisteredAllocator {
+                id: AtomicU64::new(0),
+                alloc: AtomicPtr::new(ptr::null_mut()),
+            }; MAX_ALLOCATORS],
+        }
+    }
+
+    fn entries_from(&self, id: AllocatorId) -> impl Iterator<Item = &RegisteredAllocator> {
+        let start = (id.to_u64() % MAX_ALLOCATORS as u64) as usize;
+        self.entries[start..].iter().chain(&self.entries[..start])
+    }
+
+    /// Finds an open allocator by id.
+    pub(crate) fn find(&self, id: AllocatorId) -> Option<&'static ShmemAllocator> {
+        self.entries_from(id).find_map(|entry| entry.get(id))
+    }
+
+    /// Finds an open allocator by name, or registers the one `open` returns.
+    ///
+    /// The lock is held while opening the allocator, so threads opening the same heap
+    /// at the same time share an allocator.
+    pub(crate) fn find_or_register(
+        &self,
+        name: &str,
+        open: impl FnOnce() -> Result<ShmemAllocator, SharedDataError>,
+    ) -> Result<&'static ShmemAllocator, SharedDataError> {
+        let _lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        // Nobody can unregister an allocator while we hold the lock, so we can look at them all.
+        let registered = self
+            .entries
+            .iter()
+            .filter_map(|entry| unsafe { entry.alloc.load(Ordering::SeqCst).as_ref() })
+            .find(|alloc| alloc.name() == name);
+        match registered {
+            Some(alloc) => Ok(alloc),
+            None => self.register_locked(open()?),
+        }
+    }
+
+    /// Registers a new allocator, which lives until it is unregistered.
+    pub(crate) fn register(
+        &self,
+        alloc: ShmemAllocator,
+    ) -> Result<&'static ShmemAllocator, SharedDataError> {
+        let _lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.register_locked(alloc)
+    }
+
+    fn register_locked(
+        &self,
+        alloc: ShmemAllocator,
+    ) -> Result<&'static ShmemAllocator, SharedDataError> {
+        let id = alloc.id();
+        let entry = self
+            .entries_from(id)
+            .find(|entry| entry.alloc.load(Ordering::SeqCst).is_null())
+            .ok_or(SharedDataError::OutOfAllocators)?;
+        let alloc = Box::into_raw(Box::new(alloc));
+        entry.id.store(id.to_u64(), Ordering::SeqCst);
+        entry.alloc.store(alloc, Ordering::SeqCst);
+        Ok(unsafe { &*alloc })
+    }
+
+    /// Unregisters an allocator, returning it if it was registered.
+    pub(crate) fn unregister(&self, alloc: &ShmemAllocator) -> Option<Box<ShmemAllocator>> {
+        let _lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let alloc = alloc as *const ShmemAllocator as *mut ShmemAllocator;
+        let entry = self
+            .entries
+            .iter()
+            .find(|entry| entry.alloc.load(Ordering::SeqCst) == alloc)?;
+        entry.alloc.store(ptr::null_mut(), Ordering::SeqCst);
+        entry.id.store(0, Ordering::SeqCst);
+        Some(unsafe { Box::from_raw(alloc) })
+    }
+
+    /// The number of registered allocators for a heap.
+    #[cfg(test)]
+    pub(crate) fn count(&self, name: &str) -> usize {
+        let _lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.entries
+            .iter()
+            .filter_map(|entry| unsafe { entry.alloc.load(Ordering::SeqCst).as_ref() })
+            .filter(|alloc| alloc.name() == name)
+            .count()
     }
 }
 
-impl From<u64> for SharedAddressRange {
-    fn from(data: u64) -> SharedAddressRange {
-        unsafe { mem::transmute(data) }
+lazy_static! {
+    pub(crate) static ref ALLOCATORS: AllocatorRegistry = AllocatorRegistry::new();
+}
+
+/// A heap used by a test, which is closed once the test is done with it,
+/// so that its shared memory isn't left behind.
+#[cfg(test)]
+pub(crate) struct TestHeap(&'static ShmemAllocator);
+
+#[cfg(test)]
+impl TestHeap {
+    pub(crate) fn new() -> TestHeap {
+        TestHeap(ShmemAllocator::create().expect("Failed to create shared memory"))
     }
 }
 
-impl From<SharedAddressRange> for u64 {
-    fn from(address: SharedAddressRange) -> u64 {
-        unsafe { mem::transmute(address) }
+#[cfg(test)]
+impl Deref for TestHeap {
+    type Target = ShmemAllocator;
+    fn deref(&self) -> &ShmemAllocator {
+        self.0
     }
 }
+
+#[cfg(test)]
+impl Drop for TestHeap {
+    fn drop(&mut self) {
+        // Anything borrowed from the allocator borrows the heap, so it is no longer in use.
+        unsafe { ShmemAllocator::close(self.0) }
+    }
+}
+
+/// Convert a slice of volatile values that implement `SharedMemRef`
+/// into a slice of values.
+pub fn slice_from_volatile<T: SharedMemCast + SharedMemRef>(slice: &[Volatile<T>]) -> &[T] {
+    unsafe { mem::transmute(slice) }
+}
+
+// Is there a way to do this with safe code?
+pub(crate) fn slice_empty<'a, T: 'a>() -> &'a [T] {
+    unsafe { slice::from_raw_parts(ptr::NonNull::dangling().as_ptr(), 0) }
+}
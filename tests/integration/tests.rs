@@ -2,16 +2,21 @@
 use crate::harness::spawn_child;
 use num_derive::FromPrimitive;
 use num_derive::ToPrimitive;
-#[cfg(not(test))]
 use shared_data::SharedAddressRange;
-#[cfg(test)]
 use shared_data::SharedBox;
-#[cfg(test)]
+use shared_data::SharedHashMap;
+use shared_data::SharedRc;
 use shared_data::SharedVec;
 #[cfg(test)]
+use shared_data::ShmemAllocator;
+#[cfg(not(test))]
+use std::convert::TryFrom;
+use std::process;
+#[cfg(not(test))]
+use std::sync::atomic::AtomicU32;
 use std::sync::atomic::AtomicUsize;
-#[cfg(test)]
 use std::sync::atomic::Ordering;
+use std::thread;
 
 // An enum of all the tests
 #[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive)]
@@ -20,6 +25,10 @@ pub enum ChildId {
     Noop,
     SharedBox,
     SharedVec,
+    AllocStress,
+    Roots,
+    LargeVec,
+    HashMap,
 }
 
 // This is run in the child process, not the main test process
@@ -31,6 +40,10 @@ impl ChildId {
             ChildId::Noop => run_noop(address),
             ChildId::SharedBox => run_shared_box(address),
             ChildId::SharedVec => run_shared_vec(address),
+            ChildId::AllocStress => run_alloc_stress(address),
+            ChildId::Roots => run_roots(address),
+            ChildId::LargeVec => run_large_vec(address),
+            ChildId::HashMap => run_hash_map(address),
         }
     }
 }
@@ -90,3 +103,141 @@ fn test_vector() {
 fn run_shared_vec(_address: SharedAddressRange) {
     // TODO
 }
+
+// Allocate and free lots of boxes, checking that no box is handed out twice.
+// Each thread must have a different id.
+fn alloc_stress(id: usize) {
+    for iteration in 0..10_000 {
+        let boxes: Vec<_> = (0..(1 + iteration % 4))
+            .map(|index| SharedBox::new(AtomicUsize::new(id * 4 + index)))
+            .collect();
+        thread::yield_now();
+        for (index, boxed) in boxes.iter().enumerate() {
+            assert_eq!(boxed.load(Ordering::SeqCst), id * 4 + index);
+        }
+    }
+}
+
+#[test]
+fn test_alloc_stress() {
+    let boxed: SharedBox<usize> = SharedBox::new(37);
+    let mut children: Vec<_> = (0..4)
+        .map(|_| spawn_child(ChildId::AllocStress, boxed.address()))
+        .collect();
+    let threads: Vec<_> = (0..4)
+        .map(|index| thread::spawn(move || alloc_stress(process::id() as usize * 8 + 1 + index)))
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    for child in &mut children {
+        assert!(child.wait().unwrap().success());
+    }
+}
+
+#[cfg(not(test))]
+fn run_alloc_stress(_address: SharedAddressRange) {
+    alloc_stress(process::id() as usize * 8);
+}
+
+#[test]
+fn test_roots() {
+    shared_data::publish("test-roots", SharedRc::new(AtomicUsize::new(37))).unwrap();
+    let boxed: SharedBox<usize> = SharedBox::new(37);
+    let mut child = spawn_child(ChildId::Roots, boxed.address());
+    assert!(child.wait().unwrap().success());
+    let rc: SharedRc<AtomicUsize> = shared_data::lookup("test-roots").unwrap();
+    assert_eq!(rc.load(Ordering::SeqCst), 38);
+    let unpublished: SharedRc<AtomicUsize> = shared_data::unpublish("test-roots").unwrap();
+    assert!(SharedRc::ptr_eq(&rc, &unpublished));
+    assert!(shared_data::lookup::<AtomicUsize>("test-roots").is_err());
+}
+
+#[test]
+fn test_close() {
+    let alloc = ShmemAllocator::create().unwrap();
+    let name = alloc.name();
+    // Opening the heap again in the same process gives the same allocator
+    let opened = ShmemAllocator::open(&name).unwrap();
+    assert!(std::ptr::eq(alloc, opened));
+    let boxed = SharedBox::new_in(AtomicUsize::new(37), alloc);
+    unsafe { ShmemAllocator::close(alloc) };
+    // Containers can no longer find their allocator, and the heap has been unlinked
+    assert!(boxed.try_get().is_err());
+    assert!(ShmemAllocator::open(&name).is_err());
+}
+
+#[cfg(not(test))]
+fn run_roots(_address: SharedAddressRange) {
+    assert!(shared_data::lookup::<AtomicU32>("test-roots").is_err());
+    let rc: SharedRc<AtomicUsize> = shared_data::lookup("test-roots").unwrap();
+    rc.fetch_add(1, Ordering::SeqCst);
+}
+
+// Big enough that the vector gets shared memory of its own.
+const LARGE_VEC_LENGTH: usize = 1 << 20;
+
+#[test]
+fn test_large_vec() {
+    let alloc = ShmemAllocator::open(&shared_data::get_bootstrap_name()).unwrap();
+    let vec = SharedVec::from_iter((0..LARGE_VEC_LENGTH).map(AtomicUsize::new));
+    let segment = alloc
+        .stats()
+        .segments
+        .into_iter()
+        .find(|segment| segment.size > LARGE_VEC_LENGTH * 8)
+        .unwrap();
+    let mut child = spawn_child(ChildId::LargeVec, SharedAddressRange::from(vec));
+    assert!(child.wait().unwrap().success());
+    // The child freed the vector, which unlinked its shared memory
+    // even though both processes had it mapped, and its slot can be reused.
+    assert!(alloc
+        .stats()
+        .segments
+        .iter()
+        .all(|other| other.name != segment.name));
+    #[cfg(target_os = "linux")]
+    assert!(!std::path::Path::new("/dev/shm")
+        .join(segment.name.trim_start_matches('/'))
+        .exists());
+    let vec = SharedVec::from_iter((0..LARGE_VEC_LENGTH).map(|i| AtomicUsize::new(i + 1)));
+    for (i, atomic) in vec.iter().enumerate() {
+        assert_eq!(atomic.load(Ordering::SeqCst), i + 1);
+    }
+}
+
+#[cfg(not(test))]
+fn run_large_vec(address: SharedAddressRange) {
+    let vec = SharedVec::<AtomicUsize>::try_from(address).unwrap();
+    assert_eq!(vec.len(), LARGE_VEC_LENGTH);
+    for (i, atomic) in vec.iter().enumerate() {
+        assert_eq!(atomic.load(Ordering::SeqCst), i);
+    }
+}
+
+#[test]
+fn test_hash_map() {
+    let map = SharedRc::new(SharedHashMap::new());
+    for i in 0..100usize {
+        map.insert(i, i * 2);
+    }
+    let mut child = spawn_child(ChildId::HashMap, SharedRc::address(&map));
+    assert!(child.wait().unwrap().success());
+    // The child found our entries, and we can find the child's,
+    // even though it resized the map.
+    assert_eq!(map.len(), 200);
+    for i in 0..200usize {
+        assert_eq!(map.get(&i), Some(i * 2));
+    }
+}
+
+#[cfg(not(test))]
+fn run_hash_map(address: SharedAddressRange) {
+    let map = SharedRc::<SharedHashMap<usize, usize>>::try_from(address).unwrap();
+    for i in 0..100 {
+        assert_eq!(map.get(&i), Some(i * 2));
+    }
+    for i in 100..200 {
+        map.insert(i, i * 2);
+    }
+}